@@ -7,6 +7,7 @@
   pre-commit.hooks.rustfmt.enable = true;
 
   packages = with pkgs; [
+    dbus
     libusb
     tokio-console
   ];
@@ -14,6 +15,6 @@
   difftastic.enable = true;
 
   enterTest = ''
-    cargo test
+    cargo test --all-features
   '';
 }
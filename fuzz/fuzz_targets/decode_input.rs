@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code)]
+#[path = "../../src/streamdeck/input.rs"]
+mod input;
+
+use input::{Input, INPUT_REPORT_LENGTH};
+
+fuzz_target!(|data: &[u8]| {
+    // Decoding must never panic, whatever the device sends
+    let decoded = Input::decode(data);
+
+    // Anything long enough decodes the same as the fixed size report
+    if let Ok(report) =
+        <[u8; INPUT_REPORT_LENGTH]>::try_from(&data[..data.len().min(INPUT_REPORT_LENGTH)])
+    {
+        assert_eq!(decoded, Input::try_from(report));
+    }
+});
@@ -1,91 +1,334 @@
-use anyhow::Result;
+//! Running several apps on one deck, with input routed to the active one.
+//!
+//! Controls can also be routed to a specific app with [`Apps::set_route`], e.g. to keep a
+//! volume dial working whichever app is up. With [`Apps::switch_on_long_press`], long
+//! pressing the LCD switches to the next app.
+//!
+//! Each app draws through its own [view](StreamDeckPlus::view) of the deck, which only
+//! reaches the keys and LCD zones routed to it. Switching apps redraws what the new one
+//! last drew.
+
+mod routing;
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
 use tokio::{
-    sync::{broadcast, mpsc},
+    sync::mpsc,
     task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    idle::IdleHandle,
+    streamdeck::{Input, StreamDeckPlus},
+    task,
 };
 
-use crate::streamdeck::{Input, StreamDeckPlus};
+pub use self::routing::{next_app, Control, Route, RoutingTable, LCD_ZONES};
 
+/// The registered apps and which one currently receives input.
 #[derive(Debug)]
 pub struct Apps {
     pub deck: StreamDeckPlus,
     apps: Vec<AppInfo>,
     active_app: Option<usize>,
-    active_app_tx: broadcast::Sender<mpsc::UnboundedSender<Input>>,
+    routes: RoutingTable,
+    switch_order: Vec<usize>,
+    // Only there once the router is running, it starts from the state at that point
+    router_tx: Option<mpsc::UnboundedSender<RouterUpdate>>,
+    shutdown: CancellationToken,
+    router: Option<JoinHandle<Result<()>>>,
+    idle: Option<IdleHandle>,
 }
 
+/// Handle to a running app task.
 pub type AppResult = JoinHandle<Result<()>>;
-pub type AppInfo = (String, AppResult, mpsc::UnboundedSender<Input>);
+/// Name, task handle, input channel and deck view of a registered app.
+pub type AppInfo = (
+    String,
+    AppResult,
+    mpsc::UnboundedSender<Input>,
+    StreamDeckPlus,
+);
+
+// Changes sent to a running router
+#[derive(Debug)]
+enum RouterUpdate {
+    Register(mpsc::UnboundedSender<Input>, StreamDeckPlus),
+    Activate(usize),
+    Route(Control, Route),
+    SwitchOrder(Vec<usize>),
+}
 
 impl Apps {
-    pub fn new(deck: StreamDeckPlus) -> Self {
-        let (active_app_tx, _active_app_rx) = broadcast::channel(10);
+    /// Apps and the router stop once `shutdown` is cancelled.
+    pub fn new(deck: StreamDeckPlus, shutdown: CancellationToken) -> Self {
         Self {
             deck,
             apps: vec![],
             active_app: Some(0),
-            active_app_tx,
+            routes: RoutingTable::new(),
+            switch_order: vec![],
+            router_tx: None,
+            shutdown,
+            router: None,
+            idle: None,
         }
     }
 
+    /// Token for a new app, cancelled when the apps are shut down.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.child_token()
+    }
+
+    /// Adds a spawned app, usually done through [`spawn_app!`](crate::spawn_app).
+    ///
+    /// `deck` is the [view](StreamDeckPlus::view) the app draws through.
     pub fn register(
         &mut self,
         name: String,
         handle: AppResult,
         tx: mpsc::UnboundedSender<Input>,
+        deck: StreamDeckPlus,
     ) -> Result<()> {
-        self.apps.push((name, handle, tx));
+        self.apps.push((name, handle, tx.clone(), deck.clone()));
+        self.update_router(RouterUpdate::Register(tx, deck));
         Ok(())
     }
 
+    /// Index of the app registered as `name`.
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.apps.iter().position(|(app, ..)| app == name)
+    }
+
+    /// Number of registered apps.
+    pub fn len(&self) -> usize {
+        self.apps.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apps.is_empty()
+    }
+
+    /// Sends all further input to the app at `index`, apart from controls routed elsewhere.
     pub fn activate(&mut self, index: usize) -> Result<()> {
+        if index >= self.apps.len() {
+            bail!("No app at index {}", index);
+        }
+
         self.active_app = Some(index);
-        let tx = self.apps[index].2.clone();
-        self.active_app_tx.send(tx)?;
+        self.update_router(RouterUpdate::Activate(index));
         Ok(())
     }
 
-    pub fn route(&self) -> Result<()> {
+    /// Sends input from `control` to `route`, whichever app is active.
+    pub fn set_route(&mut self, control: Control, route: Route) -> Result<()> {
+        if let Route::App(index) = route {
+            if index >= self.apps.len() {
+                bail!("No app at index {}", index);
+            }
+        }
+
+        self.routes.set_route(control, route);
+        self.update_router(RouterUpdate::Route(control, route));
+        Ok(())
+    }
+
+    /// Long pressing anywhere on the LCD switches to the next app in `order`.
+    ///
+    /// Long presses no longer reach the apps then. Apps that only get routed controls, like
+    /// a mic key, are best left out since activating them shows nothing.
+    pub fn switch_on_long_press(&mut self, order: Vec<usize>) -> Result<()> {
+        if let Some(index) = order.iter().find(|index| **index >= self.apps.len()) {
+            bail!("No app at index {}", index);
+        }
+
+        self.switch_order = order.clone();
+        self.update_router(RouterUpdate::SwitchOrder(order));
+        Ok(())
+    }
+
+    // Updates are unbounded so none are lost, a router that's stopped doesn't need them
+    fn update_router(&self, update: RouterUpdate) {
+        if let Some(tx) = &self.router_tx {
+            let _ = tx.send(update);
+        }
+    }
+
+    /// Reports input to an idle manager, call before [`route`](Self::route).
+    pub fn set_idle(&mut self, idle: IdleHandle) {
+        self.idle = Some(idle);
+    }
+
+    /// Starts forwarding input from the deck to the active app.
+    pub fn route(&mut self) -> Result<()> {
+        let state = RouterState {
+            apps: self
+                .apps
+                .iter()
+                .map(|(_, _, tx, deck)| (tx.clone(), deck.clone()))
+                .collect(),
+            active_app: self.active_app,
+            routes: self.routes.clone(),
+            switch_order: self.switch_order.clone(),
+        };
         let deck = self.deck.clone();
-        let rx = self.active_app_tx.subscribe();
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.router_tx = Some(tx);
+        let shutdown = self.shutdown.child_token();
+        let idle = self.idle.clone();
 
-        tokio::task::Builder::new()
-            .name("input router")
-            .spawn(router(deck, rx))?;
+        self.router = Some(task::spawn(
+            "input router",
+            router(deck, state, rx, idle, shutdown),
+        )?);
+
+        Ok(())
+    }
+
+    /// Cancels every app and the router, then waits up to `timeout` for them to finish.
+    ///
+    /// Anything still running after that is aborted.
+    pub async fn shutdown(self, timeout: Duration) -> Result<()> {
+        self.shutdown.cancel();
+        let deadline = Instant::now() + timeout;
+
+        let router = self
+            .router
+            .map(|handle| ("input router".to_string(), handle));
+        let tasks = router.into_iter().chain(
+            self.apps
+                .into_iter()
+                .map(|(name, handle, ..)| (name, handle)),
+        );
+
+        for (name, mut handle) in tasks {
+            match timeout_at(deadline, &mut handle).await {
+                Ok(Ok(Ok(()))) => tracing::debug!("{} stopped", name),
+                Ok(Ok(Err(err))) => tracing::error!("{} failed: {:?}", name, err),
+                Ok(Err(err)) => tracing::error!("{} panicked: {:?}", name, err),
+                Err(_) => {
+                    tracing::warn!("{} didn't stop in time, aborting it", name);
+                    handle.abort();
+                }
+            }
+        }
 
         Ok(())
     }
 }
 
+// What the router knows about the apps, kept up to date through `RouterUpdate`s
+struct RouterState {
+    apps: Vec<(mpsc::UnboundedSender<Input>, StreamDeckPlus)>,
+    active_app: Option<usize>,
+    routes: RoutingTable,
+    switch_order: Vec<usize>,
+}
+
+impl RouterState {
+    fn update(&mut self, update: RouterUpdate) {
+        match update {
+            RouterUpdate::Register(tx, deck) => self.apps.push((tx, deck)),
+            RouterUpdate::Activate(index) => self.active_app = Some(index),
+            RouterUpdate::Route(control, route) => self.routes.set_route(control, route),
+            RouterUpdate::SwitchOrder(order) => self.switch_order = order,
+        }
+    }
+
+    // Activates the next app, false if there's nothing to switch between
+    fn switch_to_next(&mut self) -> bool {
+        match next_app(&self.switch_order, self.active_app) {
+            Some(next) => {
+                self.active_app = Some(next);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn send(&mut self, input: Input) {
+        for (route, input) in self.routes.split(input) {
+            let index = match route {
+                Route::Active => self.active_app,
+                Route::App(index) => Some(index),
+            };
+
+            // An app that's stopped shouldn't take the others down with it
+            let Some((tx, _)) = index.and_then(|index| self.apps.get(index)) else {
+                continue;
+            };
+            if tx.send(input).is_err() {
+                tracing::warn!("App {:?} isn't taking input anymore", index);
+            }
+        }
+    }
+
+    // Shows each app's view where its input comes from
+    async fn show(&self) {
+        for (index, (_, deck)) in self.apps.iter().enumerate() {
+            let visibility = self.routes.visibility(index, self.active_app);
+            if let Err(err) = deck.set_visibility(visibility).await {
+                tracing::warn!("Could not show app {}: {:?}", index, err);
+            }
+        }
+    }
+}
+
 async fn router(
     deck: StreamDeckPlus,
-    mut rx: broadcast::Receiver<mpsc::UnboundedSender<Input>>,
+    mut state: RouterState,
+    mut rx: mpsc::UnboundedReceiver<RouterUpdate>,
+    idle: Option<IdleHandle>,
+    shutdown: CancellationToken,
 ) -> Result<()> {
-    let (_handle, mut inputs) = deck.subscribe().unwrap();
+    let (handle, mut inputs) = deck.subscribe()?;
+    state.show().await;
 
-    let mut active_channel: Option<mpsc::UnboundedSender<Input>> = None;
     loop {
         tokio::select! {
+            _ = shutdown.cancelled() => {
+                handle.abort();
+                return Ok(());
+            }
             Some(input) = inputs.recv() => {
-                if let Some(ref chan) = active_channel {
-                    chan.send(input)?;
+                // The input that wakes the deck up only wakes it up
+                let woke = input != Input::None
+                    && idle.as_ref().is_some_and(|idle| idle.activity());
+                if woke {
+                    continue;
+                }
+
+                if matches!(input, Input::LcdLongPress { .. }) && state.switch_to_next() {
+                    state.show().await;
+                    continue;
                 }
+                state.send(input);
             }
-            Ok(new_chan) = rx.recv() => {
-                active_channel = Some(new_chan);
+            Some(update) = rx.recv() => {
+                state.update(update);
+                state.show().await;
             }
         }
     }
 }
 
+/// Spawns an app function and registers it with an [`Apps`](crate::app::Apps).
+///
+/// The function gets a [view](crate::streamdeck::StreamDeckPlus::view) of the deck, the
+/// receiving end of its input channel and a token that's cancelled on shutdown, followed
+/// by any extra arguments given.
+#[macro_export]
 macro_rules! spawn_app {
-    ($apps:ident, $name:literal, $func:ident) => {
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<crate::streamdeck::Input>();
-        let app = tokio::task::Builder::new()
-            .name($name)
-            .spawn($func($apps.deck.clone(), rx))?;
-        $apps.register($name.into(), app, tx)?;
+    ($apps:ident, $name:literal, $func:path $(, $arg:expr)* $(,)?) => {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<$crate::streamdeck::Input>();
+        let deck = $apps.deck.view();
+        let app = $crate::task::spawn(
+            $name,
+            $func(deck.clone(), rx, $apps.shutdown_token() $(, $arg)*),
+        )?;
+        $apps.register($name.into(), app, tx, deck)?;
     };
 }
-
-pub(crate) use spawn_app;
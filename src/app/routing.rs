@@ -0,0 +1,282 @@
+//! Which app each control's input goes to.
+//!
+//! Input reports cover several controls at once, so they're split up and each app only
+//! sees the controls routed to it.
+
+use std::collections::HashMap;
+
+pub use crate::streamdeck::LCD_ZONES;
+use crate::streamdeck::{Input, Visibility, KEY_COUNT, ZONE_WIDTH};
+
+/// A control on the deck that can be routed on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Control {
+    /// Key 0-7.
+    Key(u8),
+    /// Encoder 0-3, both pressing and twisting it.
+    Encoder(u8),
+    /// A quarter of the LCD, 0-3 from the left, touches and swipes starting in it.
+    LcdZone(u8),
+}
+
+impl Control {
+    /// The zone containing `x`.
+    pub fn lcd_zone_at(x: u16) -> Self {
+        Control::LcdZone((x as u32 / ZONE_WIDTH).min(LCD_ZONES as u32 - 1) as u8)
+    }
+}
+
+/// Where a control's input goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Route {
+    /// Whichever app is active.
+    #[default]
+    Active,
+    /// Always the app at this index, whether it's active or not.
+    App(usize),
+}
+
+/// Routes for each control, anything not in here goes to the active app.
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: HashMap<Control, Route>,
+    // Last states seen, so only the controls that changed are routed
+    buttons: [bool; KEY_COUNT as usize],
+    presses: [bool; 4],
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route(&self, control: Control) -> Route {
+        self.routes.get(&control).copied().unwrap_or_default()
+    }
+
+    pub fn set_route(&mut self, control: Control, route: Route) {
+        match route {
+            Route::Active => self.routes.remove(&control),
+            route => self.routes.insert(control, route),
+        };
+    }
+
+    /// Where the app at `index` draws, the keys and LCD zones its input comes from.
+    pub fn visibility(&self, index: usize, active_app: Option<usize>) -> Visibility {
+        let shows = |control| match self.route(control) {
+            Route::Active => active_app == Some(index),
+            Route::App(app) => app == index,
+        };
+        Visibility {
+            keys: std::array::from_fn(|key| shows(Control::Key(key as u8))),
+            lcd_zones: std::array::from_fn(|zone| shows(Control::LcdZone(zone as u8))),
+        }
+    }
+
+    /// Splits `input` into the part each route should get.
+    pub fn split(&mut self, input: Input) -> Vec<(Route, Input)> {
+        match input {
+            Input::None => vec![(Route::Active, input)],
+            Input::Buttons(states) => {
+                let changed = changed(&self.buttons, &states);
+                self.buttons = states;
+                self.split_states(&states, &changed, Control::Key)
+                    .into_iter()
+                    .map(|(route, states)| (route, Input::Buttons(states)))
+                    .collect()
+            }
+            Input::EncoderPress(states) => {
+                let changed = changed(&self.presses, &states);
+                self.presses = states;
+                self.split_states(&states, &changed, Control::Encoder)
+                    .into_iter()
+                    .map(|(route, states)| (route, Input::EncoderPress(states)))
+                    .collect()
+            }
+            Input::EncoderTwist(deltas) => {
+                let mut split: Vec<(Route, [i8; 4])> = vec![];
+                for (index, delta) in deltas.iter().enumerate() {
+                    if *delta == 0 {
+                        continue;
+                    }
+
+                    let route = self.route(Control::Encoder(index as u8));
+                    match split.iter_mut().find(|(r, _)| *r == route) {
+                        Some((_, routed)) => routed[index] = *delta,
+                        None => {
+                            let mut routed = [0; 4];
+                            routed[index] = *delta;
+                            split.push((route, routed));
+                        }
+                    }
+                }
+                split
+                    .into_iter()
+                    .map(|(route, deltas)| (route, Input::EncoderTwist(deltas)))
+                    .collect()
+            }
+            Input::LcdTouch { x, .. } | Input::LcdLongPress { x, .. } => {
+                vec![(self.route(Control::lcd_zone_at(x)), input)]
+            }
+            Input::LcdSwipe { from, .. } => vec![(self.route(Control::lcd_zone_at(from.0)), input)],
+        }
+    }
+
+    // Each route with a changed control gets the states of the controls routed to it,
+    // everything else reads as released
+    fn split_states<const N: usize>(
+        &self,
+        states: &[bool; N],
+        changed: &[bool; N],
+        control: fn(u8) -> Control,
+    ) -> Vec<(Route, [bool; N])> {
+        let routes: Vec<Route> = (0..N).map(|i| self.route(control(i as u8))).collect();
+
+        let mut split: Vec<(Route, [bool; N])> = vec![];
+        for (index, route) in routes.iter().enumerate() {
+            if !changed[index] || split.iter().any(|(r, _)| r == route) {
+                continue;
+            }
+
+            let mut routed = [false; N];
+            for (i, state) in states.iter().enumerate() {
+                routed[i] = *state && routes[i] == *route;
+            }
+            split.push((*route, routed));
+        }
+        split
+    }
+}
+
+/// The app after `active` in `order`, starting over at the front, for switching in turn.
+pub fn next_app(order: &[usize], active: Option<usize>) -> Option<usize> {
+    let position = active.and_then(|active| order.iter().position(|app| *app == active));
+    match position {
+        Some(position) => order.get((position + 1) % order.len()).copied(),
+        None => order.first().copied(),
+    }
+}
+
+fn changed<const N: usize>(before: &[bool; N], after: &[bool; N]) -> [bool; N] {
+    let mut changed = [false; N];
+    for (i, value) in changed.iter_mut().enumerate() {
+        *value = before[i] != after[i];
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn everything_goes_to_active_by_default() {
+        let mut table = RoutingTable::new();
+        let twist = Input::EncoderTwist([1, -2, 0, 3]);
+        assert_eq!(table.split(twist), vec![(Route::Active, twist)]);
+
+        let touch = Input::LcdTouch { x: 700, y: 50 };
+        assert_eq!(table.split(touch), vec![(Route::Active, touch)]);
+    }
+
+    #[test]
+    fn twists_are_split_per_encoder() {
+        let mut table = RoutingTable::new();
+        table.set_route(Control::Encoder(0), Route::App(2));
+
+        assert_eq!(
+            table.split(Input::EncoderTwist([1, -2, 0, 3])),
+            vec![
+                (Route::App(2), Input::EncoderTwist([1, 0, 0, 0])),
+                (Route::Active, Input::EncoderTwist([0, -2, 0, 3])),
+            ]
+        );
+        assert_eq!(
+            table.split(Input::EncoderTwist([0, 1, 0, 0])),
+            vec![(Route::Active, Input::EncoderTwist([0, 1, 0, 0]))]
+        );
+    }
+
+    #[test]
+    fn only_changed_keys_are_routed() {
+        let mut table = RoutingTable::new();
+        table.set_route(Control::Key(7), Route::App(1));
+
+        let mut keys = [false; 8];
+        keys[0] = true;
+        assert_eq!(
+            table.split(Input::Buttons(keys)),
+            vec![(Route::Active, Input::Buttons(keys))]
+        );
+
+        // Key 0 is still held, but the press on key 7 only goes to its app
+        keys[7] = true;
+        let mut routed = [false; 8];
+        routed[7] = true;
+        assert_eq!(
+            table.split(Input::Buttons(keys)),
+            vec![(Route::App(1), Input::Buttons(routed))]
+        );
+
+        // Releasing both reaches both
+        assert_eq!(
+            table.split(Input::Buttons([false; 8])),
+            vec![
+                (Route::Active, Input::Buttons([false; 8])),
+                (Route::App(1), Input::Buttons([false; 8])),
+            ]
+        );
+    }
+
+    #[test]
+    fn lcd_zones() {
+        let mut table = RoutingTable::new();
+        table.set_route(Control::LcdZone(3), Route::App(4));
+
+        let touch = Input::LcdTouch { x: 650, y: 10 };
+        assert_eq!(table.split(touch), vec![(Route::App(4), touch)]);
+
+        let swipe = Input::LcdSwipe {
+            from: (100, 50),
+            to: (700, 50),
+        };
+        assert_eq!(table.split(swipe), vec![(Route::Active, swipe)]);
+
+        table.set_route(Control::LcdZone(3), Route::Active);
+        assert_eq!(table.split(touch), vec![(Route::Active, touch)]);
+    }
+
+    #[test]
+    fn apps_draw_where_their_input_comes_from() {
+        let mut table = RoutingTable::new();
+        table.set_route(Control::Key(4), Route::App(2));
+        table.set_route(Control::Encoder(0), Route::App(2));
+
+        let active = table.visibility(0, Some(0));
+        assert_eq!(
+            active.keys,
+            [true, true, true, true, false, true, true, true]
+        );
+        assert_eq!(active.lcd_zones, [true; 4]);
+
+        // Encoders have nothing to draw on, so only the key shows
+        let mut mic = Visibility::none();
+        mic.keys[4] = true;
+        assert_eq!(table.visibility(2, Some(0)), mic);
+
+        assert!(table.visibility(1, Some(0)).is_hidden());
+        assert!(table.visibility(0, None).is_hidden());
+    }
+
+    #[test]
+    fn apps_switch_in_turn() {
+        let order = [3, 0, 1];
+        assert_eq!(next_app(&order, Some(3)), Some(0));
+        assert_eq!(next_app(&order, Some(1)), Some(3));
+
+        // Anything not in the order starts it from the front
+        assert_eq!(next_app(&order, Some(2)), Some(3));
+        assert_eq!(next_app(&order, None), Some(3));
+        assert_eq!(next_app(&[], Some(0)), None);
+    }
+}
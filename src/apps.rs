@@ -0,0 +1,93 @@
+//! Built-in apps, spawned with [`spawn_app!`](crate::spawn_app) like any other.
+
+#[cfg(all(feature = "audio", feature = "text", unix))]
+pub mod devices;
+#[cfg(all(feature = "media", feature = "text", unix))]
+pub mod media;
+#[cfg(all(feature = "audio", feature = "text", unix))]
+pub mod meters;
+#[cfg(all(feature = "audio", unix))]
+pub mod mic;
+#[cfg(all(feature = "audio", feature = "text", unix))]
+pub mod mixer;
+
+use std::path::PathBuf;
+
+use image::RgbImage;
+
+// Themes are tried in order, hicolor is the fallback every desktop has
+const ICON_DIRS: &[&str] = &[
+    "/usr/share/icons/hicolor/scalable/apps",
+    "/usr/share/icons/hicolor/256x256/apps",
+    "/usr/share/icons/hicolor/128x128/apps",
+    "/usr/share/icons/hicolor/64x64/apps",
+    "/usr/share/icons/hicolor/48x48/apps",
+    "/usr/share/pixmaps",
+];
+
+/// Looks up a freedesktop icon name, like the ones applications give PulseAudio.
+pub fn find_icon(name: &str) -> Option<PathBuf> {
+    // Some applications give a path instead of a name
+    let path = PathBuf::from(name);
+    if path.is_absolute() {
+        return path.exists().then_some(path);
+    }
+
+    let home = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".local/share/icons/hicolor/scalable/apps"));
+    let dirs = home.into_iter().chain(ICON_DIRS.iter().map(PathBuf::from));
+
+    for dir in dirs {
+        for extension in ["svg", "png"] {
+            let path = dir.join(format!("{}.{}", name, extension));
+            if path.exists() {
+                return Some(path);
+            }
+        }
+    }
+    None
+}
+
+/// Which of `states` went from released to pressed, updating `last`.
+pub fn newly_pressed<const N: usize>(last: &mut [bool; N], states: [bool; N]) -> [bool; N] {
+    let mut pressed = [false; N];
+    for (i, value) in pressed.iter_mut().enumerate() {
+        *value = states[i] && !last[i];
+    }
+    *last = states;
+    pressed
+}
+
+/// Fills a rectangle, clipped to the image.
+pub fn fill_rect(
+    img: &mut RgbImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    color: image::Rgb<u8>,
+) {
+    let (img_width, img_height) = img.dimensions();
+    for py in y..(y + height).min(img_height) {
+        for px in x..(x + width).min(img_width) {
+            img.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// A horizontal bar filled to `fraction` (0.0 to 1.0) on top of a track.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_bar(
+    img: &mut RgbImage,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    fraction: f32,
+    color: image::Rgb<u8>,
+    track: image::Rgb<u8>,
+) {
+    let filled = (width as f32 * fraction.clamp(0.0, 1.0)).round() as u32;
+    fill_rect(img, x, y, width, height, track);
+    fill_rect(img, x, y, filled, height, color);
+}
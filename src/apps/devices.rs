@@ -0,0 +1,357 @@
+//! Keys for picking the default output and input device.
+//!
+//! Sinks go on the top row and sources on the bottom row, the default one of each is
+//! highlighted. Pressing a sink also moves everything that's playing over to it.
+
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::{find_icon, newly_pressed};
+use crate::{
+    sound::{AudioCommand, AudioHandle, AudioState},
+    streamdeck::{
+        icon::IconSource,
+        key_image::{Background, Badge, KeyImage, TitlePosition},
+        Input, StreamDeckPlus, KEY_COUNT,
+    },
+};
+
+const KEYS_PER_ROW: usize = 4;
+
+const DEFAULT_TOP: image::Rgb<u8> = image::Rgb([0x1E, 0x6F, 0x3E]);
+const DEFAULT_BOTTOM: image::Rgb<u8> = image::Rgb([0x0E, 0x33, 0x1C]);
+const OTHER_BACKGROUND: image::Rgb<u8> = image::Rgb([0x20, 0x20, 0x20]);
+const DEFAULT_BADGE: image::Rgb<u8> = image::Rgb([0x2E, 0xCC, 0x71]);
+
+/// Runs the device switcher until `shutdown` is cancelled.
+pub async fn device_switcher(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+    audio: AudioHandle,
+) -> Result<()> {
+    let mut states = audio.subscribe();
+    let mut switcher = DeviceSwitcher::new(states.borrow_and_update().clone());
+    switcher.draw(&deck).await?;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            changed = states.changed() => {
+                changed?;
+                switcher.update(states.borrow_and_update().clone());
+                switcher.draw(&deck).await?;
+            }
+            Some(input) = inputs.recv() => {
+                for command in switcher.handle(input) {
+                    if let Err(err) = audio.run(command).await {
+                        tracing::warn!("Could not switch device: {:?}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A device shown on a key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceKey {
+    pub kind: DeviceKind,
+    /// What the server calls it, defaults are set by name.
+    pub name: String,
+    pub description: String,
+    pub is_default: bool,
+    icon: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    Sink,
+    Source,
+}
+
+/// The device switcher's state, separate from the deck so it can be driven directly.
+#[derive(Debug)]
+pub struct DeviceSwitcher {
+    state: AudioState,
+    keys: [Option<DeviceKey>; KEY_COUNT as usize],
+    // Icons found by name, so the icon theme is only searched once for each
+    icons: HashMap<String, Option<PathBuf>>,
+    buttons: [bool; KEY_COUNT as usize],
+    // What each key shows, so only keys that changed are redrawn
+    drawn: [Option<DeviceKey>; KEY_COUNT as usize],
+}
+
+impl DeviceSwitcher {
+    pub fn new(state: AudioState) -> Self {
+        let mut switcher = Self {
+            state: AudioState::default(),
+            keys: Default::default(),
+            icons: HashMap::new(),
+            buttons: [false; KEY_COUNT as usize],
+            drawn: Default::default(),
+        };
+        switcher.update(state);
+        switcher
+    }
+
+    pub fn update(&mut self, state: AudioState) {
+        self.state = state;
+        self.keys = self.layout();
+    }
+
+    /// What goes on each key, the rest of the devices don't fit.
+    pub fn keys(&self) -> &[Option<DeviceKey>; KEY_COUNT as usize] {
+        &self.keys
+    }
+
+    fn layout(&mut self) -> [Option<DeviceKey>; KEY_COUNT as usize] {
+        let icons = &mut self.icons;
+        let mut find = |name: &str| {
+            icons
+                .entry(name.to_string())
+                .or_insert_with(|| find_icon(name))
+                .clone()
+        };
+
+        let sinks: Vec<DeviceKey> = self
+            .state
+            .sinks
+            .iter()
+            .map(|sink| DeviceKey {
+                kind: DeviceKind::Sink,
+                name: sink.name.clone(),
+                description: sink.description.clone(),
+                is_default: self.state.default_sink.as_ref() == Some(&sink.name),
+                icon: device_icon(
+                    sink.icon_name.as_deref(),
+                    sink.form_factor.as_deref(),
+                    &mut find,
+                ),
+            })
+            .collect();
+
+        // Monitors would just be the sinks over again
+        let sources: Vec<DeviceKey> = self
+            .state
+            .sources
+            .iter()
+            .filter(|source| source.monitor_of.is_none())
+            .map(|source| DeviceKey {
+                kind: DeviceKind::Source,
+                name: source.name.clone(),
+                description: source.description.clone(),
+                is_default: self.state.default_source.as_ref() == Some(&source.name),
+                icon: device_icon(
+                    source.icon_name.as_deref(),
+                    source.form_factor.as_deref(),
+                    &mut find,
+                )
+                .or_else(|| find("audio-input-microphone")),
+            })
+            .collect();
+
+        let mut keys: [Option<DeviceKey>; KEY_COUNT as usize] = Default::default();
+        for (key, sink) in keys[..KEYS_PER_ROW].iter_mut().zip(sinks) {
+            *key = Some(sink);
+        }
+        for (key, source) in keys[KEYS_PER_ROW..].iter_mut().zip(sources) {
+            *key = Some(source);
+        }
+        keys
+    }
+
+    /// Applies input, returning the commands to send to the server.
+    pub fn handle(&mut self, input: Input) -> Vec<AudioCommand> {
+        let Input::Buttons(states) = input else {
+            return vec![];
+        };
+
+        let pressed = newly_pressed(&mut self.buttons, states);
+        let mut commands = vec![];
+        for (index, key) in self.keys.iter().enumerate() {
+            if let (true, Some(key)) = (pressed[index], key) {
+                commands.extend(self.select(key));
+            }
+        }
+        commands
+    }
+
+    // Making a sink the default only affects new streams, so move the playing ones too
+    fn select(&self, key: &DeviceKey) -> Vec<AudioCommand> {
+        match key.kind {
+            DeviceKind::Source => vec![AudioCommand::SetDefaultSource(key.name.clone())],
+            DeviceKind::Sink => {
+                let Some(sink) = self.state.sinks.iter().find(|sink| sink.name == key.name) else {
+                    return vec![];
+                };
+
+                let moves = self
+                    .state
+                    .streams
+                    .iter()
+                    .filter(|stream| stream.sink != sink.index)
+                    .map(|stream| AudioCommand::MoveStream {
+                        index: stream.index,
+                        sink: sink.index,
+                    });
+                std::iter::once(AudioCommand::SetDefaultSink(key.name.clone()))
+                    .chain(moves)
+                    .collect()
+            }
+        }
+    }
+
+    pub async fn draw(&mut self, deck: &StreamDeckPlus) -> Result<()> {
+        for (index, key) in self.keys.clone().into_iter().enumerate() {
+            if self.drawn[index] == key {
+                continue;
+            }
+
+            match key {
+                Some(ref key) => {
+                    deck.set_button_image(index as u8, &render_key(key).await?)
+                        .await?
+                }
+                None => deck.clear_key(index as u8).await?,
+            }
+            self.drawn[index] = key;
+        }
+        Ok(())
+    }
+}
+
+async fn render_key(key: &DeviceKey) -> Result<image::RgbImage> {
+    let background = if key.is_default {
+        Background::Gradient(DEFAULT_TOP, DEFAULT_BOTTOM)
+    } else {
+        Background::Color(OTHER_BACKGROUND)
+    };
+
+    // Long descriptions are cut short rather than shrinking to nothing
+    let mut img = KeyImage::new()
+        .background(background)
+        .title(&key.description, TitlePosition::Bottom)
+        .title_ellipsis(true);
+    if let Some(ref path) = key.icon {
+        img = img.icon(IconSource::file(path.clone()));
+    }
+    if key.is_default {
+        img = img.badge(Badge::Status(DEFAULT_BADGE));
+    }
+    img.render().await
+}
+
+// The device's own icon, or a generic one for its form factor
+fn device_icon(
+    icon_name: Option<&str>,
+    form_factor: Option<&str>,
+    find: &mut impl FnMut(&str) -> Option<PathBuf>,
+) -> Option<PathBuf> {
+    if let Some(path) = icon_name.and_then(&mut *find) {
+        return Some(path);
+    }
+
+    let generic = match form_factor? {
+        "headset" | "headphone" | "hands-free" => "audio-headset",
+        "speaker" | "internal" => "audio-speakers",
+        "tv" | "monitor" => "video-display",
+        "microphone" | "webcam" => "audio-input-microphone",
+        _ => "audio-card",
+    };
+    find(generic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::fixtures::{sink, source, stream};
+
+    fn state() -> AudioState {
+        AudioState {
+            sinks: vec![sink(1, "speakers"), sink(2, "headset")],
+            sources: vec![
+                source(10, "speakers.monitor", Some(1)),
+                source(11, "mic", None),
+                source(12, "headset.monitor", Some(2)),
+                source(13, "webcam", None),
+            ],
+            streams: vec![stream(20, 1), stream(21, 2), stream(22, 1)],
+            default_sink: Some("speakers".to_string()),
+            default_source: Some("webcam".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn press(index: usize) -> Input {
+        let mut states = [false; KEY_COUNT as usize];
+        states[index] = true;
+        Input::Buttons(states)
+    }
+
+    #[test]
+    fn sinks_on_top_and_sources_below() {
+        let switcher = DeviceSwitcher::new(state());
+        let keys = switcher.keys();
+        let names: Vec<_> = keys
+            .iter()
+            .map(|key| key.as_ref().map(|key| (key.kind, key.name.as_str())))
+            .collect();
+        assert_eq!(
+            names,
+            [
+                Some((DeviceKind::Sink, "speakers")),
+                Some((DeviceKind::Sink, "headset")),
+                None,
+                None,
+                Some((DeviceKind::Source, "mic")),
+                Some((DeviceKind::Source, "webcam")),
+                None,
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn defaults_are_highlighted() {
+        let switcher = DeviceSwitcher::new(state());
+        let keys = switcher.keys();
+        let defaults: Vec<_> = keys
+            .iter()
+            .flatten()
+            .filter(|key| key.is_default)
+            .map(|key| key.name.as_str())
+            .collect();
+        assert_eq!(defaults, ["speakers", "webcam"]);
+    }
+
+    #[test]
+    fn selecting_a_sink_moves_streams_from_other_sinks() {
+        let mut switcher = DeviceSwitcher::new(state());
+        assert_eq!(
+            switcher.handle(press(1)),
+            [
+                AudioCommand::SetDefaultSink("headset".to_string()),
+                AudioCommand::MoveStream { index: 20, sink: 2 },
+                AudioCommand::MoveStream { index: 22, sink: 2 },
+            ]
+        );
+
+        // Only on the press, not while it's held
+        assert!(switcher.handle(press(1)).is_empty());
+    }
+
+    #[test]
+    fn selecting_a_source() {
+        let mut switcher = DeviceSwitcher::new(state());
+        assert_eq!(
+            switcher.handle(press(4)),
+            [AudioCommand::SetDefaultSource("mic".to_string())]
+        );
+        // Keys without a device do nothing
+        assert!(switcher.handle(press(7)).is_empty());
+    }
+}
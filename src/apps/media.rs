@@ -0,0 +1,448 @@
+//! Media keys and a now playing zone for whichever MPRIS player is active.
+//!
+//! Keys 0-2 are previous, play/pause and next. The first LCD zone shows the album art,
+//! title, artist and progress. Its encoder seeks, or changes the volume after pressing it.
+
+use std::{path::PathBuf, time::Duration, time::Instant};
+
+use anyhow::Result;
+use image::{imageops, RgbImage};
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use super::{draw_bar, newly_pressed};
+use crate::{
+    media::{MediaCommand, MediaHandle, MediaState, PlaybackStatus, Player},
+    streamdeck::{
+        encoder::{Encoder, EncoderConfig},
+        key_image::{KeyImage, TitlePosition},
+        text::{font_renderer, TextStyle},
+        Input, StreamDeckPlus, KEY_COUNT, LCD_HEIGHT, ZONE_WIDTH,
+    },
+};
+
+const PREVIOUS_KEY: u8 = 0;
+const PLAY_PAUSE_KEY: u8 = 1;
+const NEXT_KEY: u8 = 2;
+// The zone and the encoder below it
+const ZONE: usize = 0;
+// Seconds to seek and volume change for one click, before acceleration
+const SEEK_STEP: f64 = 5.0;
+const VOLUME_STEP: f64 = 0.02;
+const ART_SIZE: u32 = 76;
+
+const BAR_COLOR: image::Rgb<u8> = image::Rgb([0x1D, 0xB9, 0x54]);
+const TRACK_COLOR: image::Rgb<u8> = image::Rgb([0x30, 0x30, 0x30]);
+const DIM_TEXT: image::Rgb<u8> = image::Rgb([0xA0, 0xA0, 0xA0]);
+
+/// Runs the media app until `shutdown` is cancelled.
+pub async fn media(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+    media: MediaHandle,
+) -> Result<()> {
+    let mut states = media.subscribe();
+    let mut app = Media::new(states.borrow_and_update().clone());
+    app.draw(&deck).await?;
+
+    // Progress moves on its own while playing
+    let mut ticks = tokio::time::interval(Duration::from_secs(1));
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            changed = states.changed() => {
+                changed?;
+                app.update(states.borrow_and_update().clone());
+                app.draw(&deck).await?;
+            }
+            _ = ticks.tick() => {
+                if app.is_playing() {
+                    app.draw_zone(&deck).await?;
+                }
+            }
+            Some(input) = inputs.recv() => {
+                for command in app.handle(input) {
+                    // Players can refuse, like seeking in a live stream
+                    if let Err(err) = media.run(command).await {
+                        tracing::debug!("Media command failed: {:?}", err);
+                    }
+                }
+                app.draw(&deck).await?;
+            }
+        }
+    }
+}
+
+/// What twisting the encoder does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncoderMode {
+    #[default]
+    Seek,
+    Volume,
+}
+
+/// The media app's state, separate from the deck so it can be driven directly.
+#[derive(Debug)]
+pub struct Media {
+    state: MediaState,
+    mode: EncoderMode,
+    encoder: Encoder,
+    buttons: [bool; KEY_COUNT as usize],
+    presses: [bool; 4],
+    // What the keys show, so they're only redrawn when it changes
+    drawn_keys: Option<Option<PlaybackStatus>>,
+    // Art for the current URL, `None` if it couldn't be loaded
+    art: Option<(String, Option<RgbImage>)>,
+}
+
+impl Media {
+    pub fn new(state: MediaState) -> Self {
+        Self {
+            state,
+            mode: EncoderMode::default(),
+            encoder: Encoder::new(EncoderConfig::new().accelerated()),
+            buttons: [false; KEY_COUNT as usize],
+            presses: [false; 4],
+            drawn_keys: None,
+            art: None,
+        }
+    }
+
+    pub fn update(&mut self, state: MediaState) {
+        self.state = state;
+    }
+
+    pub fn mode(&self) -> EncoderMode {
+        self.mode
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.state
+            .active()
+            .is_some_and(|player| player.status == PlaybackStatus::Playing)
+    }
+
+    /// Applies input, returning the commands to send to the player.
+    pub fn handle(&mut self, input: Input) -> Vec<MediaCommand> {
+        match input {
+            Input::Buttons(states) => {
+                let pressed = newly_pressed(&mut self.buttons, states);
+                [
+                    (PREVIOUS_KEY, MediaCommand::Previous),
+                    (PLAY_PAUSE_KEY, MediaCommand::PlayPause),
+                    (NEXT_KEY, MediaCommand::Next),
+                ]
+                .into_iter()
+                .filter(|(key, _)| pressed[*key as usize])
+                .map(|(_, command)| command)
+                .collect()
+            }
+            Input::EncoderPress(states) => {
+                if newly_pressed(&mut self.presses, states)[ZONE] {
+                    self.mode = match self.mode {
+                        EncoderMode::Seek => EncoderMode::Volume,
+                        EncoderMode::Volume => EncoderMode::Seek,
+                    };
+                }
+                vec![]
+            }
+            Input::EncoderTwist(deltas) if deltas[ZONE] != 0 => {
+                let delta = self.encoder.twist(deltas[ZONE]);
+                self.twist(delta).into_iter().collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    fn twist(&mut self, delta: f64) -> Option<MediaCommand> {
+        let mode = self.mode;
+        let player = self.state.active_mut()?;
+
+        match mode {
+            EncoderMode::Seek => Some(MediaCommand::Seek((delta * SEEK_STEP * 1e6) as i64)),
+            EncoderMode::Volume => {
+                // Updated here too, so quick twists add up before the player catches up.
+                // MPRIS allows over 100%, which can be turned down but not further up.
+                let max = player.volume.max(1.0);
+                player.volume = (player.volume + delta * VOLUME_STEP).clamp(0.0, max);
+                Some(MediaCommand::SetVolume(player.volume))
+            }
+        }
+    }
+
+    pub async fn draw(&mut self, deck: &StreamDeckPlus) -> Result<()> {
+        let keys = self.state.active().map(|player| player.status);
+        if self.drawn_keys != Some(keys) {
+            self.draw_keys(deck, keys).await?;
+            self.drawn_keys = Some(keys);
+        }
+        self.draw_zone(deck).await
+    }
+
+    async fn draw_keys(&self, deck: &StreamDeckPlus, status: Option<PlaybackStatus>) -> Result<()> {
+        let Some(status) = status else {
+            for key in [PREVIOUS_KEY, PLAY_PAUSE_KEY, NEXT_KEY] {
+                deck.clear_key(key).await?;
+            }
+            return Ok(());
+        };
+
+        let play_pause = match status {
+            PlaybackStatus::Playing => "||",
+            _ => "\u{25B6}",
+        };
+        let keys = [
+            (PREVIOUS_KEY, "\u{25C0}\u{25C0}"),
+            (PLAY_PAUSE_KEY, play_pause),
+            (NEXT_KEY, "\u{25B6}\u{25B6}"),
+        ];
+        for (key, title) in keys {
+            let img = KeyImage::new()
+                .title(title, TitlePosition::Middle)
+                .render()
+                .await?;
+            deck.set_button_image(key, &img).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn draw_zone(&mut self, deck: &StreamDeckPlus) -> Result<()> {
+        let img = self.render_zone(Instant::now()).await;
+        deck.set_lcd_image((ZONE as u32 * ZONE_WIDTH) as u16, 0, &img)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn render_zone(&mut self, now: Instant) -> RgbImage {
+        let mut img = RgbImage::new(ZONE_WIDTH, LCD_HEIGHT);
+        let padding = 8;
+
+        let Some(player) = self.state.active().cloned() else {
+            let style = TextStyle::new().size(16.0).color(DIM_TEXT).centered();
+            font_renderer().lock().await.draw_text(
+                &mut img,
+                0,
+                0,
+                ZONE_WIDTH,
+                LCD_HEIGHT,
+                "Nothing playing",
+                &style,
+            );
+            return img;
+        };
+
+        let mut text_x = padding;
+        if let Some(art) = self.art(&player).await {
+            imageops::overlay(&mut img, art, padding as i64, padding as i64);
+            text_x += ART_SIZE + padding;
+        }
+
+        let title = player.track.title.as_deref().unwrap_or(player.name());
+        let artist = player.track.artists.join(", ");
+        let detail = match self.mode {
+            EncoderMode::Seek => time_line(&player, now),
+            EncoderMode::Volume => format!("Volume {:.0}%", player.volume * 100.0),
+        };
+
+        let text_width = ZONE_WIDTH - padding - text_x;
+        let title_style = TextStyle::new()
+            .size(16.0)
+            .color(image::Rgb([0xFF, 0xFF, 0xFF]))
+            .ellipsis(true);
+        let detail_style = TextStyle::new().size(13.0).color(DIM_TEXT).ellipsis(true);
+
+        let mut renderer = font_renderer().lock().await;
+        let lines = [
+            (title, &title_style),
+            (&artist, &detail_style),
+            (&detail, &detail_style),
+        ];
+        for (line, (text, style)) in lines.into_iter().enumerate() {
+            let y = padding + line as u32 * 24;
+            renderer.draw_text(
+                &mut img,
+                text_x as i32,
+                y as i32,
+                text_width,
+                22,
+                text,
+                style,
+            );
+        }
+        drop(renderer);
+
+        let progress = match self.mode {
+            EncoderMode::Seek => player.progress_at(now).unwrap_or_default(),
+            EncoderMode::Volume => player.volume as f32,
+        };
+        draw_bar(
+            &mut img,
+            padding,
+            LCD_HEIGHT - padding - 4,
+            ZONE_WIDTH - padding * 2,
+            4,
+            progress,
+            BAR_COLOR,
+            TRACK_COLOR,
+        );
+        img
+    }
+
+    // Loaded once per track, only local art is supported
+    async fn art(&mut self, player: &Player) -> Option<&RgbImage> {
+        let url = player.track.art_url.as_deref()?;
+        if self.art.as_ref().map(|(cached, _)| cached.as_str()) != Some(url) {
+            let art = load_art(url).await;
+            if art.is_none() {
+                tracing::debug!("Could not load album art from {}", url);
+            }
+            self.art = Some((url.to_string(), art));
+        }
+        self.art.as_ref()?.1.as_ref()
+    }
+}
+
+async fn load_art(url: &str) -> Option<RgbImage> {
+    let path = file_url_path(url)?;
+
+    // Covers can be big, so they're decoded off the runtime
+    tokio::task::spawn_blocking(move || {
+        let art = image::open(path).ok()?.to_rgb8();
+        Some(imageops::resize(
+            &art,
+            ART_SIZE,
+            ART_SIZE,
+            imageops::FilterType::Triangle,
+        ))
+    })
+    .await
+    .ok()?
+}
+
+// The path of a file:// URL, with %XX escapes decoded
+fn file_url_path(url: &str) -> Option<PathBuf> {
+    let path = url.strip_prefix("file://")?.as_bytes();
+
+    let mut decoded = Vec::with_capacity(path.len());
+    let mut i = 0;
+    while i < path.len() {
+        let escaped = (path[i] == b'%')
+            .then(|| std::str::from_utf8(path.get(i + 1..i + 3)?).ok())
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(path[i]);
+                i += 1;
+            }
+        }
+    }
+    Some(PathBuf::from(String::from_utf8(decoded).ok()?))
+}
+
+// Like 1:23 / 4:56, or just the position if the length isn't known
+fn time_line(player: &Player, now: Instant) -> String {
+    let position = format_time(player.position_at(now));
+    match player.track.length {
+        Some(length) => format!("{} / {}", position, format_time(length)),
+        None => position,
+    }
+}
+
+fn format_time(time: Duration) -> String {
+    let secs = time.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{golden::assert_golden, golden::init_fonts, media::Track};
+
+    fn playing(track: Track, volume: f64) -> MediaState {
+        let mut state = MediaState::default();
+        state.upsert(Player {
+            bus_name: "org.mpris.MediaPlayer2.vlc".to_string(),
+            status: PlaybackStatus::Playing,
+            track,
+            volume,
+            position: Duration::from_secs(72),
+            read_at: Instant::now(),
+        });
+        state
+    }
+
+    #[test]
+    fn decodes_file_urls() {
+        assert_eq!(
+            file_url_path("file:///home/me/My%20Music/cover.jpg"),
+            Some(PathBuf::from("/home/me/My Music/cover.jpg"))
+        );
+        assert_eq!(
+            file_url_path("file:///tmp/100%.png"),
+            Some(PathBuf::from("/tmp/100%.png"))
+        );
+        assert_eq!(file_url_path("https://example.com/cover.jpg"), None);
+    }
+
+    #[test]
+    fn formats_times() {
+        assert_eq!(format_time(Duration::from_secs(5)), "0:05");
+        assert_eq!(format_time(Duration::from_secs(754)), "12:34");
+    }
+
+    #[test]
+    fn keys_and_encoder_make_commands() {
+        let mut media = Media::new(MediaState::default());
+
+        let mut keys = [false; 8];
+        keys[PLAY_PAUSE_KEY as usize] = true;
+        assert_eq!(
+            media.handle(Input::Buttons(keys)),
+            [MediaCommand::PlayPause]
+        );
+        // Holding it doesn't repeat
+        assert!(media.handle(Input::Buttons(keys)).is_empty());
+
+        // Nothing to seek without a player
+        assert!(media.handle(Input::EncoderTwist([1, 0, 0, 0])).is_empty());
+
+        media.handle(Input::EncoderPress([true, false, false, false]));
+        assert_eq!(media.mode(), EncoderMode::Volume);
+    }
+
+    #[test]
+    fn boosted_volume_is_not_cut_to_full() {
+        let mut media = Media::new(playing(Track::default(), 1.5));
+        media.handle(Input::EncoderPress([true, false, false, false]));
+
+        assert_eq!(
+            media.handle(Input::EncoderTwist([1, 0, 0, 0])),
+            [MediaCommand::SetVolume(1.5)]
+        );
+    }
+
+    #[tokio::test]
+    async fn now_playing_zone() {
+        init_fonts();
+        let state = playing(
+            Track {
+                title: Some("Across the Universe".to_string()),
+                artists: vec!["The Beatles".to_string()],
+                length: Some(Duration::from_secs(228)),
+                ..Default::default()
+            },
+            1.0,
+        );
+        let now = state.active().unwrap().read_at;
+        let img = Media::new(state).render_zone(now).await;
+        assert_golden("media_now_playing", &img);
+    }
+}
@@ -0,0 +1,397 @@
+//! Level meters for the default output and microphone on the LCD.
+//!
+//! Each zone records peaks from one device, outputs through their monitor source. Meters
+//! fall back slowly and hold their peak for a moment, like a hardware VU meter. Nothing is
+//! recorded or drawn while the meters aren't on screen.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use image::RgbImage;
+use tokio::{sync::mpsc, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use super::fill_rect;
+use crate::{
+    sound::{AudioHandle, AudioState, PeakStream},
+    streamdeck::{
+        text::{font_renderer, TextStyle},
+        Input, StreamDeckPlus, LCD_HEIGHT, LCD_WIDTH, ZONE_WIDTH,
+    },
+};
+
+const METER_COUNT: usize = 4;
+// Redrawing more often than this just keeps the deck busy
+const FPS: u64 = 20;
+// How fast the level falls, in meter widths a second
+const DECAY: f32 = 1.5;
+const PEAK_HOLD: Duration = Duration::from_secs(1);
+// Quietest level shown, in dB
+const FLOOR_DB: f32 = -60.0;
+
+const GREEN: image::Rgb<u8> = image::Rgb([0x2E, 0xCC, 0x71]);
+const YELLOW: image::Rgb<u8> = image::Rgb([0xF1, 0xC4, 0x0F]);
+const RED: image::Rgb<u8> = image::Rgb([0xE7, 0x4C, 0x3C]);
+const TRACK_COLOR: image::Rgb<u8> = image::Rgb([0x30, 0x30, 0x30]);
+const PEAK_COLOR: image::Rgb<u8> = image::Rgb([0xFF, 0xFF, 0xFF]);
+
+/// Runs the meters until `shutdown` is cancelled.
+pub async fn level_meters(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+    audio: AudioHandle,
+) -> Result<()> {
+    let mut visibility = deck.visibility();
+    let mut states = audio.subscribe();
+    let mut meters = Meters::default();
+    // Streams are only open while the meters are on screen
+    let mut shown = false;
+
+    let mut frames = tokio::time::interval(Duration::from_millis(1000 / FPS));
+    frames.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        let visible = !visibility.borrow_and_update().is_hidden();
+        if visible != shown {
+            shown = visible;
+            if shown {
+                let state = states.borrow_and_update().clone();
+                meters.retarget(&audio, &state).await;
+            } else {
+                meters.close();
+            }
+        }
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            Ok(()) = visibility.changed() => {}
+            changed = states.changed() => {
+                changed?;
+                let state = states.borrow_and_update().clone();
+                if shown {
+                    meters.retarget(&audio, &state).await;
+                }
+            }
+            now = frames.tick(), if shown => {
+                if meters.tick(now.into_std()) {
+                    deck.set_lcd_image(0, 0, &meters.render().await).await?;
+                }
+            }
+            // Nothing to press, but the channel still needs emptying
+            Some(_) = inputs.recv() => {}
+        }
+    }
+}
+
+/// A device being metered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeterTarget {
+    pub label: String,
+    /// The source recorded from, the monitor for sinks.
+    pub source: u32,
+}
+
+/// The devices to meter, default output and input first.
+pub fn meter_targets(state: &AudioState) -> Vec<MeterTarget> {
+    let default_sink = state.default_sink().map(|sink| sink.index);
+    let default_source = state.default_source().map(|source| source.index);
+
+    let mut sinks: Vec<_> = state.sinks.iter().collect();
+    sinks.sort_by_key(|sink| Some(sink.index) != default_sink);
+    let mut sources: Vec<_> = state
+        .sources
+        .iter()
+        .filter(|source| source.monitor_of.is_none())
+        .collect();
+    sources.sort_by_key(|source| Some(source.index) != default_source);
+
+    let sinks = sinks.into_iter().filter_map(|sink| {
+        let monitor = state
+            .sources
+            .iter()
+            .find(|source| source.monitor_of == Some(sink.index))?;
+        Some(MeterTarget {
+            label: sink.description.clone(),
+            source: monitor.index,
+        })
+    });
+    let sources = sources.into_iter().map(|source| MeterTarget {
+        label: source.description.clone(),
+        source: source.index,
+    });
+
+    // Interleaved, so one of each is shown before any second ones
+    let mut sinks = sinks.fuse();
+    let mut sources = sources.fuse();
+    let mut targets = vec![];
+    while targets.len() < METER_COUNT {
+        let next = [sinks.next(), sources.next()];
+        if next.iter().all(Option::is_none) {
+            break;
+        }
+        targets.extend(next.into_iter().flatten());
+    }
+    targets.truncate(METER_COUNT);
+    targets
+}
+
+/// A meter's displayed level, falling back smoothly between peaks.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MeterLevel {
+    /// 0.0 to 1.0 of the meter's width.
+    pub level: f32,
+    /// The highest recent level.
+    pub peak: f32,
+    peak_at: Option<Instant>,
+    updated_at: Option<Instant>,
+}
+
+impl MeterLevel {
+    /// Takes the loudest sample since the last update, linear 0.0 to 1.0.
+    pub fn update_at(&mut self, sample: f32, now: Instant) {
+        let elapsed = self
+            .updated_at
+            .map(|at| now.duration_since(at).as_secs_f32())
+            .unwrap_or_default();
+        self.updated_at = Some(now);
+
+        let fallen = (self.level - DECAY * elapsed).max(0.0);
+        self.level = meter_fraction(sample).max(fallen);
+
+        let held = self
+            .peak_at
+            .is_some_and(|at| now.duration_since(at) < PEAK_HOLD);
+        if self.level >= self.peak || !held {
+            self.peak = self.level;
+            self.peak_at = Some(now);
+        }
+    }
+}
+
+/// Where a linear level sits on the meter, which is in dB like every other meter.
+pub fn meter_fraction(sample: f32) -> f32 {
+    if sample <= 0.0 {
+        return 0.0;
+    }
+    let db = 20.0 * sample.log10();
+    ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0)
+}
+
+#[derive(Debug, Default)]
+struct Meters {
+    meters: Vec<(MeterTarget, Option<PeakStream>, MeterLevel)>,
+    // Bar widths last drawn, to skip frames that look the same
+    drawn: Option<Vec<(u32, u32)>>,
+}
+
+impl Meters {
+    // Opens streams for the devices that should be metered now, if they've changed
+    async fn retarget(&mut self, audio: &AudioHandle, state: &AudioState) {
+        let targets = meter_targets(state);
+        let current: Vec<&MeterTarget> = self.meters.iter().map(|(target, ..)| target).collect();
+
+        // Streams die with the connection, so after a restart they're opened again
+        let killed = self
+            .meters
+            .iter()
+            .any(|(_, stream, _)| stream.as_ref().is_some_and(PeakStream::is_killed));
+        if targets.iter().eq(current) && !killed {
+            return;
+        }
+
+        // Streams still wanted are kept, dropping the rest closes them
+        let mut old = std::mem::take(&mut self.meters);
+        for target in targets {
+            let kept = old.iter().position(|(t, stream, _)| {
+                t.source == target.source && stream.as_ref().is_some_and(|s| !s.is_killed())
+            });
+            let stream = match kept {
+                Some(index) => old.swap_remove(index).1,
+                None => match audio.record_peaks(target.source).await {
+                    Ok(stream) => Some(stream),
+                    Err(err) => {
+                        tracing::warn!("Could not meter {}: {:?}", target.label, err);
+                        None
+                    }
+                },
+            };
+            self.meters.push((target, stream, MeterLevel::default()));
+        }
+        self.drawn = None;
+    }
+
+    // Stops metering, dropping the streams closes them
+    fn close(&mut self) {
+        self.meters.clear();
+        self.drawn = None;
+    }
+
+    // Takes the latest peaks, true if the meters look any different
+    fn tick(&mut self, now: Instant) -> bool {
+        for (_, stream, level) in &mut self.meters {
+            let sample = stream
+                .as_ref()
+                .map(PeakStream::take_peak)
+                .unwrap_or_default();
+            level.update_at(sample, now);
+        }
+
+        let bar_width = ZONE_WIDTH - 16;
+        let widths: Vec<(u32, u32)> = self
+            .meters
+            .iter()
+            .map(|(.., level)| {
+                let width = |fraction: f32| (fraction * bar_width as f32).round() as u32;
+                (width(level.level), width(level.peak))
+            })
+            .collect();
+        if self.drawn.as_ref() == Some(&widths) {
+            return false;
+        }
+        self.drawn = Some(widths);
+        true
+    }
+
+    async fn render(&self) -> RgbImage {
+        let mut img = RgbImage::new(LCD_WIDTH, LCD_HEIGHT);
+        let padding = 8;
+        let label_style = TextStyle::new()
+            .size(16.0)
+            .color(image::Rgb([0xFF, 0xFF, 0xFF]))
+            .ellipsis(true);
+
+        let mut renderer = font_renderer().lock().await;
+        for (zone, (target, stream, level)) in self.meters.iter().enumerate() {
+            let x = zone as u32 * ZONE_WIDTH;
+            let width = ZONE_WIDTH - padding * 2;
+            renderer.draw_text(
+                &mut img,
+                (x + padding) as i32,
+                padding as i32,
+                width,
+                24,
+                &target.label,
+                &label_style,
+            );
+
+            if stream.is_none() {
+                continue;
+            }
+            draw_meter(&mut img, x + padding, 48, width, 24, level);
+        }
+        img
+    }
+}
+
+// Green, then yellow and red towards the top, with a line at the held peak
+fn draw_meter(img: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, level: &MeterLevel) {
+    let filled = (width as f32 * level.level).round() as u32;
+    let yellow_at = (width as f32 * 0.75) as u32;
+    let red_at = (width as f32 * 0.9) as u32;
+
+    fill_rect(img, x, y, width, height, TRACK_COLOR);
+    fill_rect(img, x, y, filled.min(yellow_at), height, GREEN);
+    if filled > yellow_at {
+        fill_rect(
+            img,
+            x + yellow_at,
+            y,
+            filled.min(red_at) - yellow_at,
+            height,
+            YELLOW,
+        );
+    }
+    if filled > red_at {
+        fill_rect(img, x + red_at, y, filled - red_at, height, RED);
+    }
+
+    let peak = (width as f32 * level.peak).round() as u32;
+    if peak > 0 {
+        let peak = peak.clamp(2, width);
+        fill_rect(img, x + peak - 2, y, 2, height, PEAK_COLOR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::ms,
+        golden::assert_golden,
+        sound::fixtures::{sink, source},
+    };
+
+    #[test]
+    fn defaults_come_first() {
+        let state = AudioState {
+            sinks: vec![sink(0, "speakers"), sink(1, "headphones")],
+            sources: vec![
+                source(10, "speakers.monitor", Some(0)),
+                source(11, "headphones.monitor", Some(1)),
+                source(12, "webcam", None),
+                source(13, "mic", None),
+            ],
+            default_sink: Some("headphones".to_string()),
+            default_source: Some("mic".to_string()),
+            ..Default::default()
+        };
+
+        let sources: Vec<u32> = meter_targets(&state).iter().map(|t| t.source).collect();
+        assert_eq!(sources, [11, 13, 10, 12]);
+    }
+
+    #[test]
+    fn sinks_without_monitors_are_skipped() {
+        let state = AudioState {
+            sinks: vec![sink(0, "speakers")],
+            sources: vec![source(12, "mic", None)],
+            ..Default::default()
+        };
+
+        let sources: Vec<u32> = meter_targets(&state).iter().map(|t| t.source).collect();
+        assert_eq!(sources, [12]);
+    }
+
+    #[test]
+    fn levels_are_in_db() {
+        assert_eq!(meter_fraction(0.0), 0.0);
+        assert_eq!(meter_fraction(1.0), 1.0);
+        assert_eq!(meter_fraction(0.001), 0.0);
+        assert!((meter_fraction(0.1) - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn levels_fall_back_and_hold_peaks() {
+        let mut level = MeterLevel::default();
+        let start = Instant::now();
+
+        level.update_at(1.0, start);
+        assert_eq!((level.level, level.peak), (1.0, 1.0));
+
+        // Silence only pulls it down so fast, the peak stays put for a while
+        level.update_at(0.0, start + ms(200));
+        assert!((level.level - 0.7).abs() < 1e-6);
+        assert_eq!(level.peak, 1.0);
+
+        level.update_at(0.0, start + ms(1200));
+        assert_eq!(level.level, 0.0);
+        assert_eq!(level.peak, 0.0);
+    }
+
+    #[test]
+    fn bars_change_color_towards_the_top() {
+        let mut img = RgbImage::new(LCD_WIDTH, LCD_HEIGHT);
+        let levels = [(0.0, 0.3), (0.5, 0.6), (0.8, 0.85), (0.95, 1.0)];
+        for (zone, (level, peak)) in levels.into_iter().enumerate() {
+            let level = MeterLevel {
+                level,
+                peak,
+                ..Default::default()
+            };
+            let x = zone as u32 * ZONE_WIDTH + 8;
+            draw_meter(&mut img, x, 48, ZONE_WIDTH - 16, 24, &level);
+        }
+        assert_golden("meters_bars", &img);
+    }
+}
@@ -0,0 +1,242 @@
+//! A key that mutes the microphone, red while muted and green while live.
+//!
+//! It acts on whatever the default source is, and follows mutes made elsewhere, like
+//! from a meeting app. With push to talk on, holding the key while muted only unmutes
+//! until it's let go.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    sound::{AudioCommand, AudioHandle, AudioState},
+    streamdeck::{Input, StreamDeckPlus},
+};
+
+const MUTED_COLOR: image::Rgb<u8> = image::Rgb([0xC0, 0x20, 0x20]);
+const LIVE_COLOR: image::Rgb<u8> = image::Rgb([0x20, 0xA0, 0x40]);
+// No default source to mute
+const MISSING_COLOR: image::Rgb<u8> = image::Rgb([0x30, 0x30, 0x30]);
+
+/// Which key to use and how it behaves.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MicKeyConfig {
+    pub key: u8,
+    /// Holding the key at least this long while muted talks until it's released, shorter
+    /// presses just toggle. `None` always toggles.
+    pub push_to_talk: Option<Duration>,
+}
+
+impl MicKeyConfig {
+    pub fn new(key: u8) -> Self {
+        Self {
+            key,
+            ..Default::default()
+        }
+    }
+
+    pub fn push_to_talk(mut self, hold: Duration) -> Self {
+        self.push_to_talk = Some(hold);
+        self
+    }
+}
+
+/// Runs the mic key until `shutdown` is cancelled.
+pub async fn mic_key(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+    audio: AudioHandle,
+    config: MicKeyConfig,
+) -> Result<()> {
+    let mut states = audio.subscribe();
+    let mut mic = MicKey::new(config, &states.borrow_and_update());
+    mic.draw(&deck).await?;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            changed = states.changed() => {
+                changed?;
+                mic.update(&states.borrow_and_update());
+                mic.draw(&deck).await?;
+            }
+            Some(input) = inputs.recv() => {
+                if let Some(command) = mic.handle(&input) {
+                    if let Err(err) = audio.run(command).await {
+                        tracing::warn!("Could not mute the microphone: {:?}", err);
+                    }
+                }
+                mic.draw(&deck).await?;
+            }
+        }
+    }
+}
+
+/// The mic key's state, separate from the deck so it can be driven directly.
+#[derive(Debug)]
+pub struct MicKey {
+    config: MicKeyConfig,
+    // Index and mute of the default source
+    source: Option<(u32, bool)>,
+    // When the key went down, and whether it was muted then
+    pressed: Option<(Instant, bool)>,
+    drawn: Option<image::Rgb<u8>>,
+}
+
+impl MicKey {
+    pub fn new(config: MicKeyConfig, state: &AudioState) -> Self {
+        let mut mic = Self {
+            config,
+            source: None,
+            pressed: None,
+            drawn: None,
+        };
+        mic.update(state);
+        mic
+    }
+
+    pub fn update(&mut self, state: &AudioState) {
+        self.source = state
+            .default_source()
+            .map(|source| (source.index, source.muted));
+    }
+
+    /// Whether the microphone is muted, `None` if there isn't one.
+    pub fn muted(&self) -> Option<bool> {
+        self.source.map(|(_, muted)| muted)
+    }
+
+    /// Applies input, returning the command to send to the server.
+    pub fn handle(&mut self, input: &Input) -> Option<AudioCommand> {
+        self.handle_at(input, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, input: &Input, now: Instant) -> Option<AudioCommand> {
+        let Input::Buttons(states) = input else {
+            return None;
+        };
+        let down = *states.get(self.config.key as usize)?;
+
+        match (down, self.pressed) {
+            // Pressing always toggles, so push to talk goes live right away
+            (true, None) => {
+                let muted = self.muted()?;
+                self.pressed = Some((now, muted));
+                self.set_mute(!muted)
+            }
+            (false, Some((pressed_at, was_muted))) => {
+                self.pressed = None;
+                let hold = self.config.push_to_talk?;
+                if was_muted && now.duration_since(pressed_at) >= hold {
+                    self.set_mute(true)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    // Updated here too, so the key changes color before the server confirms
+    fn set_mute(&mut self, mute: bool) -> Option<AudioCommand> {
+        let (index, muted) = self.source.as_mut()?;
+        *muted = mute;
+        Some(AudioCommand::SetSourceMute {
+            index: *index,
+            mute,
+        })
+    }
+
+    pub fn color(&self) -> image::Rgb<u8> {
+        match self.muted() {
+            Some(true) => MUTED_COLOR,
+            Some(false) => LIVE_COLOR,
+            None => MISSING_COLOR,
+        }
+    }
+
+    pub async fn draw(&mut self, deck: &StreamDeckPlus) -> Result<()> {
+        let color = self.color();
+        if self.drawn != Some(color) {
+            deck.set_button_color(self.config.key, color).await?;
+            self.drawn = Some(color);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::ms,
+        sound::{fixtures::source, Source},
+    };
+
+    fn state(muted: bool) -> AudioState {
+        AudioState {
+            sources: vec![Source {
+                muted,
+                ..source(3, "mic", None)
+            }],
+            default_source: Some("mic".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn key(down: bool) -> Input {
+        let mut states = [false; 8];
+        states[2] = down;
+        Input::Buttons(states)
+    }
+
+    fn mute(mute: bool) -> Option<AudioCommand> {
+        Some(AudioCommand::SetSourceMute { index: 3, mute })
+    }
+
+    #[test]
+    fn presses_toggle() {
+        let mut mic = MicKey::new(MicKeyConfig::new(2), &state(false));
+        let start = Instant::now();
+
+        assert_eq!(mic.handle_at(&key(true), start), mute(true));
+        assert_eq!(mic.muted(), Some(true));
+        assert_eq!(mic.handle_at(&key(false), start + ms(2000)), None);
+        assert_eq!(mic.handle_at(&key(true), start + ms(3000)), mute(false));
+        assert_eq!(mic.handle_at(&key(false), start + ms(3100)), None);
+        assert_eq!(mic.muted(), Some(false));
+    }
+
+    #[test]
+    fn holding_while_muted_talks_until_released() {
+        let config = MicKeyConfig::new(2).push_to_talk(ms(400));
+        let mut mic = MicKey::new(config, &state(true));
+        let start = Instant::now();
+
+        assert_eq!(mic.handle_at(&key(true), start), mute(false));
+        assert_eq!(mic.handle_at(&key(false), start + ms(1000)), mute(true));
+
+        // A tap still toggles
+        assert_eq!(mic.handle_at(&key(true), start + ms(2000)), mute(false));
+        assert_eq!(mic.handle_at(&key(false), start + ms(2100)), None);
+        assert_eq!(mic.muted(), Some(false));
+    }
+
+    #[test]
+    fn follows_external_mutes() {
+        let mut mic = MicKey::new(MicKeyConfig::new(2), &state(false));
+        assert_eq!(mic.color(), LIVE_COLOR);
+
+        mic.update(&state(true));
+        assert_eq!(mic.color(), MUTED_COLOR);
+        assert_eq!(mic.handle_at(&key(true), Instant::now()), mute(false));
+        assert_eq!(mic.handle_at(&key(false), Instant::now()), None);
+
+        mic.update(&AudioState::default());
+        assert_eq!(mic.color(), MISSING_COLOR);
+        assert_eq!(mic.handle_at(&key(true), Instant::now()), None);
+    }
+}
@@ -0,0 +1,519 @@
+//! Volume dials for each application playing audio.
+//!
+//! Streams are spread over the four encoders, a page at a time. Twisting changes the
+//! volume, pressing toggles mute, and keys 3 and 7 or swiping the LCD change page.
+
+use std::{collections::HashMap, time::Instant};
+
+use anyhow::Result;
+use image::{imageops, RgbImage};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use super::{draw_bar, find_icon, newly_pressed};
+use crate::{
+    sound::{AudioCommand, AudioHandle, AudioState, Stream},
+    streamdeck::{
+        encoder::{EncoderConfig, Encoders},
+        icon::{flatten, icon_cache, IconSource},
+        key_image::{KeyImage, TitlePosition},
+        text::{font_renderer, TextStyle},
+        Input, StreamDeckPlus, LCD_HEIGHT, LCD_WIDTH, ZONE_WIDTH,
+    },
+};
+
+const STREAMS_PER_PAGE: usize = 4;
+const PREVIOUS_PAGE_KEY: u8 = 3;
+const NEXT_PAGE_KEY: u8 = 7;
+// Volume change for one click, before acceleration
+const VOLUME_STEP: f64 = 0.02;
+// How close the server's volume has to be to one we sent, it rounds to its own steps
+const VOLUME_ECHO_TOLERANCE: f32 = 0.005;
+const ICON_SIZE: u32 = 32;
+
+const BAR_COLOR: image::Rgb<u8> = image::Rgb([0x2E, 0xCC, 0x71]);
+const MUTED_COLOR: image::Rgb<u8> = image::Rgb([0x80, 0x30, 0x30]);
+const TRACK_COLOR: image::Rgb<u8> = image::Rgb([0x30, 0x30, 0x30]);
+
+/// Runs the mixer until `shutdown` is cancelled.
+pub async fn mixer(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+    audio: AudioHandle,
+) -> Result<()> {
+    let mut states = audio.subscribe();
+    let mut mixer = Mixer::new(states.borrow_and_update().clone());
+    mixer.draw(&deck).await?;
+
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            changed = states.changed() => {
+                changed?;
+                mixer.update(states.borrow_and_update().clone());
+                mixer.draw(&deck).await?;
+            }
+            Some(input) = inputs.recv() => {
+                for command in mixer.handle(input) {
+                    // A stream can go away between drawing and twisting, that's fine
+                    if let Err(err) = audio.run(command.clone()).await {
+                        tracing::debug!("Mixer command failed: {:?}", err);
+                        mixer.failed(&command);
+                    }
+                }
+                mixer.draw(&deck).await?;
+            }
+        }
+    }
+}
+
+/// The mixer's state, separate from the deck so it can be driven directly.
+#[derive(Debug)]
+pub struct Mixer {
+    state: AudioState,
+    page: usize,
+    encoders: Encoders,
+    // Last input states, to act on presses rather than on releases
+    presses: [bool; 4],
+    buttons: [bool; 8],
+    // Volumes sent but not yet seen back from the server, by stream
+    pending: HashMap<u32, f32>,
+    // What's on the page keys, so they're only redrawn when it changes
+    drawn_keys: Option<(usize, usize)>,
+}
+
+impl Mixer {
+    pub fn new(state: AudioState) -> Self {
+        Self {
+            state,
+            page: 0,
+            encoders: Encoders::uniform(EncoderConfig::new().step(VOLUME_STEP).accelerated()),
+            presses: [false; 4],
+            buttons: [false; 8],
+            pending: HashMap::new(),
+            drawn_keys: None,
+        }
+    }
+
+    /// Takes the server's state, keeping volumes it hasn't caught up with yet.
+    pub fn update(&mut self, mut state: AudioState) {
+        self.pending
+            .retain(|index, _| state.streams.iter().any(|s| s.index == *index));
+        for stream in &mut state.streams {
+            let Some(&volume) = self.pending.get(&stream.index) else {
+                continue;
+            };
+            if (stream.volume - volume).abs() < VOLUME_ECHO_TOLERANCE {
+                self.pending.remove(&stream.index);
+            } else {
+                stream.volume = volume;
+            }
+        }
+        self.state = state;
+        self.page = self.page.min(self.page_count() - 1);
+    }
+
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.state.streams.len().div_ceil(STREAMS_PER_PAGE).max(1)
+    }
+
+    /// The streams on the current page, by encoder.
+    pub fn slots(&self) -> [Option<&Stream>; STREAMS_PER_PAGE] {
+        let mut slots = [None; STREAMS_PER_PAGE];
+        let streams = self.state.streams.iter().skip(self.page * STREAMS_PER_PAGE);
+        for (slot, stream) in slots.iter_mut().zip(streams) {
+            *slot = Some(stream);
+        }
+        slots
+    }
+
+    /// Drops what a command the server refused would have changed.
+    pub fn failed(&mut self, command: &AudioCommand) {
+        if let AudioCommand::SetStreamVolume { index, .. } = command {
+            self.pending.remove(index);
+        }
+    }
+
+    /// Applies input, returning the commands to send to the server.
+    pub fn handle(&mut self, input: Input) -> Vec<AudioCommand> {
+        self.handle_at(input, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, input: Input, now: Instant) -> Vec<AudioCommand> {
+        match input {
+            Input::EncoderTwist(_) => {
+                let Some(deltas) = self.encoders.handle_at(&input, now) else {
+                    return vec![];
+                };
+                self.twist(deltas)
+            }
+            Input::EncoderPress(states) => {
+                let pressed = newly_pressed(&mut self.presses, states);
+                let slots = self.slots();
+                pressed
+                    .iter()
+                    .zip(slots)
+                    .filter_map(|(pressed, stream)| pressed.then_some(stream).flatten())
+                    .map(|stream| AudioCommand::SetStreamMute {
+                        index: stream.index,
+                        mute: !stream.muted,
+                    })
+                    .collect()
+            }
+            Input::Buttons(states) => {
+                let pressed = newly_pressed(&mut self.buttons, states);
+                if pressed[PREVIOUS_PAGE_KEY as usize] {
+                    self.turn_page(-1);
+                }
+                if pressed[NEXT_PAGE_KEY as usize] {
+                    self.turn_page(1);
+                }
+                vec![]
+            }
+            Input::LcdSwipe { from, to } => {
+                // Swiping left brings in the next page, like turning one
+                self.turn_page(if to.0 < from.0 { 1 } else { -1 });
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn twist(&mut self, deltas: [f64; 4]) -> Vec<AudioCommand> {
+        let slots: Vec<Option<u32>> = self.slots().iter().map(|s| s.map(|s| s.index)).collect();
+
+        let mut commands = vec![];
+        for (delta, index) in deltas.iter().zip(slots) {
+            let Some(index) = index.filter(|_| *delta != 0.0) else {
+                continue;
+            };
+            let Some(stream) = self.state.streams.iter_mut().find(|s| s.index == index) else {
+                continue;
+            };
+
+            // Updated here too, so quick twists add up before the server catches up.
+            // Volumes already over 100% can be turned down, but not further up.
+            let max = stream.volume.max(1.0);
+            stream.volume = (stream.volume + *delta as f32).clamp(0.0, max);
+            self.pending.insert(index, stream.volume);
+            commands.push(AudioCommand::SetStreamVolume {
+                index,
+                volume: stream.volume,
+            });
+        }
+        commands
+    }
+
+    fn turn_page(&mut self, by: isize) {
+        let pages = self.page_count() as isize;
+        self.page = (self.page as isize + by).rem_euclid(pages) as usize;
+    }
+
+    pub async fn draw(&mut self, deck: &StreamDeckPlus) -> Result<()> {
+        deck.set_lcd_image(0, 0, &self.render_lcd().await).await?;
+
+        let keys = (self.page, self.page_count());
+        if self.drawn_keys != Some(keys) {
+            self.draw_page_keys(deck).await?;
+            self.drawn_keys = Some(keys);
+        }
+        Ok(())
+    }
+
+    pub async fn render_lcd(&self) -> RgbImage {
+        let mut img = RgbImage::new(LCD_WIDTH, LCD_HEIGHT);
+        for (zone, stream) in self.slots().iter().enumerate() {
+            if let Some(stream) = stream {
+                self.render_zone(&mut img, zone as u32 * ZONE_WIDTH, stream)
+                    .await;
+            }
+        }
+        img
+    }
+
+    async fn render_zone(&self, img: &mut RgbImage, x: u32, stream: &Stream) {
+        let padding = 8;
+        let mut name_x = x + padding;
+
+        if let Some(path) = stream.icon_name.as_deref().and_then(find_icon) {
+            let icon = icon_cache().load(&IconSource::file(path), ICON_SIZE).await;
+            match icon {
+                Ok(icon) => {
+                    let icon = flatten(&icon, ICON_SIZE, ICON_SIZE, image::Rgb([0, 0, 0]));
+                    imageops::overlay(img, &icon, name_x as i64, padding as i64);
+                    name_x += ICON_SIZE + padding;
+                }
+                Err(err) => tracing::debug!("Could not load icon for {}: {:?}", stream.name, err),
+            }
+        }
+
+        let text_color = if stream.muted {
+            image::Rgb([0x80, 0x80, 0x80])
+        } else {
+            image::Rgb([0xFF, 0xFF, 0xFF])
+        };
+        let name_style = TextStyle::new().size(18.0).color(text_color).ellipsis(true);
+        let level_style = TextStyle::new().size(16.0).color(text_color).centered();
+        let level = if stream.muted {
+            "Muted".to_string()
+        } else {
+            format!("{:.0}%", stream.volume * 100.0)
+        };
+
+        let mut renderer = font_renderer().lock().await;
+        renderer.draw_text(
+            img,
+            name_x as i32,
+            padding as i32,
+            x + ZONE_WIDTH - padding - name_x,
+            ICON_SIZE,
+            self.state.stream_label(stream),
+            &name_style,
+        );
+        renderer.draw_text(img, x as i32, 72, ZONE_WIDTH, 24, &level, &level_style);
+
+        let color = if stream.muted { MUTED_COLOR } else { BAR_COLOR };
+        draw_bar(
+            img,
+            x + padding,
+            54,
+            ZONE_WIDTH - padding * 2,
+            12,
+            stream.volume,
+            color,
+            TRACK_COLOR,
+        );
+    }
+
+    async fn draw_page_keys(&self, deck: &StreamDeckPlus) -> Result<()> {
+        let pages = self.page_count();
+        if pages == 1 {
+            deck.clear_key(PREVIOUS_PAGE_KEY).await?;
+            deck.clear_key(NEXT_PAGE_KEY).await?;
+            return Ok(());
+        }
+
+        let page = format!("{}/{}", self.page + 1, pages);
+        for (key, arrow) in [(PREVIOUS_PAGE_KEY, "\u{25C0}"), (NEXT_PAGE_KEY, "\u{25B6}")] {
+            let img = KeyImage::new()
+                .title(format!("{} {}", arrow, page), TitlePosition::Middle)
+                .render()
+                .await?;
+            deck.set_button_image(key, &img).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        golden::{assert_golden, init_fonts},
+        sound::fixtures,
+    };
+
+    fn stream(index: u32, volume: f32) -> Stream {
+        Stream {
+            volume,
+            ..fixtures::stream(index, 0)
+        }
+    }
+
+    fn state(count: u32) -> AudioState {
+        AudioState {
+            streams: (0..count).map(|index| stream(index, 0.5)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn key(index: u8, down: bool) -> Input {
+        let mut states = [false; 8];
+        states[index as usize] = down;
+        Input::Buttons(states)
+    }
+
+    fn slot_indexes(mixer: &Mixer) -> Vec<Option<u32>> {
+        mixer.slots().iter().map(|s| s.map(|s| s.index)).collect()
+    }
+
+    #[test]
+    fn keys_and_swipes_turn_pages() {
+        let mut mixer = Mixer::new(state(6));
+        assert_eq!(mixer.page_count(), 2);
+        assert_eq!(slot_indexes(&mixer), [Some(0), Some(1), Some(2), Some(3)]);
+
+        mixer.handle(key(NEXT_PAGE_KEY, true));
+        mixer.handle(key(NEXT_PAGE_KEY, false));
+        assert_eq!(mixer.page(), 1);
+        assert_eq!(slot_indexes(&mixer), [Some(4), Some(5), None, None]);
+
+        // Turning past either end wraps around
+        mixer.handle(key(NEXT_PAGE_KEY, true));
+        mixer.handle(key(NEXT_PAGE_KEY, false));
+        assert_eq!(mixer.page(), 0);
+        mixer.handle(key(PREVIOUS_PAGE_KEY, true));
+        assert_eq!(mixer.page(), 1);
+
+        // Swiping left brings in the next page
+        mixer.handle(Input::LcdSwipe {
+            from: (600, 50),
+            to: (100, 50),
+        });
+        assert_eq!(mixer.page(), 0);
+    }
+
+    #[test]
+    fn updates_keep_the_page_in_range() {
+        let mut mixer = Mixer::new(state(6));
+        mixer.handle(key(NEXT_PAGE_KEY, true));
+        assert_eq!(mixer.page(), 1);
+
+        mixer.update(state(3));
+        assert_eq!(mixer.page(), 0);
+        mixer.update(AudioState::default());
+        assert_eq!(mixer.page_count(), 1);
+        assert_eq!(mixer.page(), 0);
+    }
+
+    #[test]
+    fn twists_add_up_before_the_server_catches_up() {
+        let mut mixer = Mixer::new(state(2));
+        let start = Instant::now();
+        let twist = Input::EncoderTwist([0, 1, 0, 0]);
+
+        // Slow enough not to accelerate
+        let volume = |command: &[AudioCommand]| match command {
+            [AudioCommand::SetStreamVolume { index: 1, volume }] => *volume,
+            other => panic!("Expected a volume for stream 1, got {:?}", other),
+        };
+        let first = volume(&mixer.handle_at(twist, start));
+        let second = volume(&mixer.handle_at(twist, start + Duration::from_secs(1)));
+        assert!((first - 0.52).abs() < 1e-4, "{}", first);
+        assert!((second - 0.54).abs() < 1e-4, "{}", second);
+    }
+
+    #[test]
+    fn volume_is_clamped() {
+        let mut mixer = Mixer::new(AudioState {
+            streams: vec![stream(0, 0.99), stream(1, 0.01)],
+            ..Default::default()
+        });
+
+        assert_eq!(
+            mixer.handle(Input::EncoderTwist([1, -1, 0, 0])),
+            [
+                AudioCommand::SetStreamVolume {
+                    index: 0,
+                    volume: 1.0
+                },
+                AudioCommand::SetStreamVolume {
+                    index: 1,
+                    volume: 0.0
+                },
+            ]
+        );
+
+        // Twisting an empty slot does nothing
+        assert!(mixer.handle(Input::EncoderTwist([0, 0, 1, 0])).is_empty());
+    }
+
+    #[test]
+    fn boosted_volume_is_not_cut_to_full() {
+        let mut mixer = Mixer::new(AudioState {
+            streams: vec![stream(0, 1.5)],
+            ..Default::default()
+        });
+        let start = Instant::now();
+        let volume = |mixer: &mut Mixer, twist, at| match mixer.handle_at(twist, at)[..] {
+            [AudioCommand::SetStreamVolume { volume, .. }] => volume,
+            ref other => panic!("Expected a volume, got {:?}", other),
+        };
+
+        assert_eq!(
+            volume(&mut mixer, Input::EncoderTwist([1, 0, 0, 0]), start),
+            1.5
+        );
+        let lowered = volume(
+            &mut mixer,
+            Input::EncoderTwist([-1, 0, 0, 0]),
+            start + Duration::from_secs(1),
+        );
+        assert!((lowered - 1.48).abs() < 1e-4, "{}", lowered);
+    }
+
+    #[test]
+    fn twisted_volumes_survive_stale_updates() {
+        let mut mixer = Mixer::new(state(1));
+        let start = Instant::now();
+        let twist = Input::EncoderTwist([1, 0, 0, 0]);
+        mixer.handle_at(twist, start);
+        mixer.handle_at(twist, start + Duration::from_secs(1));
+        let volume = |mixer: &Mixer| mixer.slots()[0].unwrap().volume;
+
+        // The server only got as far as the first twist
+        mixer.update(AudioState {
+            streams: vec![stream(0, 0.52)],
+            ..Default::default()
+        });
+        assert!((volume(&mixer) - 0.54).abs() < 1e-4);
+
+        // Once it catches up, changes from elsewhere show again
+        mixer.update(AudioState {
+            streams: vec![stream(0, 0.54)],
+            ..Default::default()
+        });
+        mixer.update(state(1));
+        assert_eq!(volume(&mixer), 0.5);
+    }
+
+    #[tokio::test]
+    async fn lcd_shows_a_zone_per_stream() {
+        init_fonts();
+        let state = AudioState {
+            streams: vec![
+                Stream {
+                    app_name: Some("Firefox".to_string()),
+                    ..stream(0, 0.8)
+                },
+                Stream {
+                    app_name: Some("A player with a very long name".to_string()),
+                    ..stream(1, 0.35)
+                },
+                Stream {
+                    muted: true,
+                    ..stream(2, 0.5)
+                },
+            ],
+            ..Default::default()
+        };
+        let img = Mixer::new(state).render_lcd().await;
+        assert_golden("mixer_lcd", &img);
+    }
+
+    #[test]
+    fn presses_toggle_mute() {
+        let mut mixer = Mixer::new(state(3));
+        let press = Input::EncoderPress([false, false, true, false]);
+
+        assert_eq!(
+            mixer.handle(press),
+            [AudioCommand::SetStreamMute {
+                index: 2,
+                mute: true
+            }]
+        );
+        // Holding it doesn't repeat, and empty slots have nothing to mute
+        assert!(mixer.handle(press).is_empty());
+        assert!(mixer
+            .handle(Input::EncoderPress([false, false, true, true]))
+            .is_empty());
+    }
+}
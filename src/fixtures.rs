@@ -0,0 +1,7 @@
+//! Helpers shared by tests across the crate.
+
+use std::time::Duration;
+
+pub fn ms(millis: u64) -> Duration {
+    Duration::from_millis(millis)
+}
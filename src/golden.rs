@@ -0,0 +1,149 @@
+//! Snapshot testing for rendered images.
+//!
+//! Rendered images are compared against PNGs in tests/golden. Set GOLDEN_BLESS=1 to
+//! create or overwrite them with the current output, a missing one fails otherwise. On a
+//! mismatch the actual image and a diff are written to target/golden-diffs.
+
+use std::path::{Path, PathBuf};
+
+use image::{Rgb, RgbImage};
+
+use crate::streamdeck::text::{font_renderer, FontConfig};
+
+#[derive(Debug, Clone, Copy)]
+pub struct Tolerance {
+    // How far a channel can be off before the pixel counts as different
+    pub channel: u8,
+    // Fraction of pixels allowed to be different
+    pub pixels: f32,
+}
+
+impl Default for Tolerance {
+    // Loose enough for antialiasing differences between platforms
+    fn default() -> Self {
+        Self {
+            channel: 24,
+            pixels: 0.005,
+        }
+    }
+}
+
+// Rendering has to use the bundled fonts so the output doesn't depend on the machine
+pub fn font_config() -> FontConfig {
+    #[cfg(feature = "bundled-fonts")]
+    let config = FontConfig::bundled();
+    #[cfg(not(feature = "bundled-fonts"))]
+    let config = FontConfig::system();
+    config
+}
+
+/// Sets up the font renderer with [`font_config`], safe to call from every test.
+pub fn init_fonts() {
+    font_renderer();
+}
+
+pub fn assert_golden(name: &str, actual: &RgbImage) {
+    assert_golden_with(name, actual, Tolerance::default());
+}
+
+pub fn assert_golden_with(name: &str, actual: &RgbImage, tolerance: Tolerance) {
+    let golden_path = golden_dir().join(format!("{}.png", name));
+
+    if blessing() {
+        save(actual, &golden_path);
+        return;
+    }
+
+    // New snapshots are only written when blessing, so nothing passes unchecked
+    if !golden_path.exists() {
+        let actual_path = write_failure(name, actual, None);
+        panic!(
+            "Missing golden image {}, actual written to {}, run with GOLDEN_BLESS=1 to create it",
+            golden_path.display(),
+            actual_path.display()
+        );
+    }
+
+    let expected = image::open(&golden_path)
+        .unwrap_or_else(|err| panic!("Could not read {}: {}", golden_path.display(), err))
+        .to_rgb8();
+
+    if expected.dimensions() != actual.dimensions() {
+        let actual_path = write_failure(name, actual, None);
+        panic!(
+            "Golden image {} is {:?} but rendered {:?}, actual written to {}",
+            name,
+            expected.dimensions(),
+            actual.dimensions(),
+            actual_path.display()
+        );
+    }
+
+    let (diff, different) = diff(&expected, actual, tolerance.channel);
+    let total = (actual.width() * actual.height()) as f32;
+    if different as f32 / total > tolerance.pixels {
+        let actual_path = write_failure(name, actual, Some(&diff));
+        panic!(
+            "Golden image {} has {} different pixels ({:.2}%), actual and diff written to {}",
+            name,
+            different,
+            different as f32 / total * 100.0,
+            actual_path.parent().unwrap().display()
+        );
+    }
+}
+
+fn blessing() -> bool {
+    std::env::var("GOLDEN_BLESS").is_ok_and(|value| value != "0" && !value.is_empty())
+}
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn diff_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target/golden-diffs")
+}
+
+// Differences in red over a faded copy of the expected image
+fn diff(expected: &RgbImage, actual: &RgbImage, threshold: u8) -> (RgbImage, usize) {
+    let mut different = 0;
+    let img = RgbImage::from_fn(expected.width(), expected.height(), |x, y| {
+        let a = expected.get_pixel(x, y);
+        let b = actual.get_pixel(x, y);
+        let distance =
+            a.0.iter()
+                .zip(b.0.iter())
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap_or(0);
+
+        if distance > threshold {
+            different += 1;
+            Rgb([0xFF, 0, 0])
+        } else {
+            let luma = (a[0] as u32 + a[1] as u32 + a[2] as u32) / 3;
+            let faded = (luma / 4) as u8;
+            Rgb([faded, faded, faded])
+        }
+    });
+    (img, different)
+}
+
+fn write_failure(name: &str, actual: &RgbImage, diff: Option<&RgbImage>) -> PathBuf {
+    let dir = diff_dir();
+    let actual_path = dir.join(format!("{}.actual.png", name));
+    save(actual, &actual_path);
+    if let Some(diff) = diff {
+        save(diff, &dir.join(format!("{}.diff.png", name)));
+    }
+    actual_path
+}
+
+fn save(img: &RgbImage, path: &Path) {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    img.save(path)
+        .unwrap_or_else(|err| panic!("Could not write {}: {}", path.display(), err));
+}
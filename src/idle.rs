@@ -0,0 +1,348 @@
+//! Dimming the deck and showing a screensaver when it hasn't been touched for a while.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use image::{imageops, RgbImage};
+use tokio::{sync::Notify, task::JoinHandle, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    streamdeck::{StreamDeckPlus, KEY_COUNT, KEY_SIZE, LCD_HEIGHT, LCD_WIDTH},
+    task,
+};
+
+/// What to show on the deck once it's been idle long enough.
+#[derive(Debug, Clone)]
+pub enum Screensaver {
+    /// The current time on the LCD, with the keys turned off.
+    #[cfg(feature = "text")]
+    Clock,
+    /// Cycles through images, each one spread across the keys and the LCD.
+    Slideshow {
+        images: Vec<RgbImage>,
+        interval: Duration,
+    },
+}
+
+/// When to dim and when to start the screensaver, measured from the last input.
+#[derive(Debug, Clone)]
+pub struct IdleConfig {
+    /// Brightness to step down to after each idle time, in increasing order.
+    pub dim_steps: Vec<(Duration, u8)>,
+    pub screensaver: Option<(Duration, Screensaver)>,
+    /// Keep the input that wakes the deck up from reaching the active app.
+    pub swallow_wake_input: bool,
+}
+
+impl Default for IdleConfig {
+    fn default() -> Self {
+        Self {
+            dim_steps: vec![
+                (Duration::from_secs(60), 50),
+                (Duration::from_secs(5 * 60), 10),
+            ],
+            screensaver: None,
+            swallow_wake_input: true,
+        }
+    }
+}
+
+/// Shared with the router so it can report input.
+#[derive(Debug, Clone)]
+pub struct IdleHandle {
+    state: Arc<IdleState>,
+}
+
+#[derive(Debug)]
+struct IdleState {
+    last_input: Mutex<Instant>,
+    idle: AtomicBool,
+    wake: Notify,
+    swallow_wake_input: bool,
+}
+
+impl IdleHandle {
+    /// Records input, waking the deck up if it was idle.
+    ///
+    /// Returns true when the input woke the deck and shouldn't be passed on.
+    pub fn activity(&self) -> bool {
+        *self.state.last_input.lock().unwrap() = Instant::now();
+
+        let was_idle = self.state.idle.swap(false, Ordering::AcqRel);
+        self.state.wake.notify_one();
+        was_idle && self.state.swallow_wake_input
+    }
+}
+
+/// Spawns the task that dims the deck and runs the screensaver.
+pub fn spawn_idle(
+    deck: StreamDeckPlus,
+    config: IdleConfig,
+    shutdown: CancellationToken,
+) -> Result<(IdleHandle, JoinHandle<Result<()>>)> {
+    let state = Arc::new(IdleState {
+        last_input: Mutex::new(Instant::now()),
+        idle: AtomicBool::new(false),
+        wake: Notify::new(),
+        swallow_wake_input: config.swallow_wake_input,
+    });
+    let handle = IdleHandle {
+        state: state.clone(),
+    };
+
+    let task = task::spawn("idle manager", idle_manager(deck, config, state, shutdown))?;
+    Ok((handle, task))
+}
+
+async fn idle_manager(
+    deck: StreamDeckPlus,
+    config: IdleConfig,
+    state: Arc<IdleState>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    // How many dim steps have been applied, and the brightness to go back to
+    let mut steps_applied = 0;
+    let mut normal_brightness = deck.brightness();
+    let mut screensaver: Option<(CancellationToken, JoinHandle<Result<()>>)> = None;
+
+    loop {
+        let last_input = *state.last_input.lock().unwrap();
+
+        // The next thing that's due, if there's anything left to do
+        let next_step = config.dim_steps.get(steps_applied).map(|(after, _)| *after);
+        let next_screensaver = match (&config.screensaver, &screensaver) {
+            (Some((after, _)), None) => Some(*after),
+            _ => None,
+        };
+        let next = match (next_step, next_screensaver) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+
+        let deadline = next.map(|after| last_input + after);
+        let sleep = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = shutdown.cancelled() => {
+                // Drawing is suspended for every handle, so restoring the deck would do nothing
+                if let Some((token, handle)) = screensaver.take() {
+                    token.cancel();
+                    handle.await??;
+                    deck.resume_drawing().await?;
+                }
+                return Ok(());
+            }
+            _ = state.wake.notified() => {
+                let was_idle = steps_applied > 0 || screensaver.is_some();
+                if !was_idle {
+                    continue;
+                }
+
+                tracing::debug!("Waking up from idle");
+                if let Some((token, handle)) = screensaver.take() {
+                    token.cancel();
+                    handle.await??;
+                    deck.resume_drawing().await?;
+                }
+                if steps_applied > 0 {
+                    deck.set_brightness(normal_brightness).await?;
+                }
+                steps_applied = 0;
+            }
+            _ = sleep => {
+                if steps_applied == 0 && screensaver.is_none() {
+                    normal_brightness = deck.brightness();
+                }
+                state.idle.store(true, Ordering::Release);
+
+                let idle_for = last_input.elapsed();
+                while let Some((after, brightness)) = config.dim_steps.get(steps_applied) {
+                    if *after > idle_for {
+                        break;
+                    }
+                    tracing::debug!("Idle for {:?}, dimming to {}", after, brightness);
+                    deck.set_brightness(*brightness).await?;
+                    steps_applied += 1;
+                }
+
+                if let Some((after, ref kind)) = config.screensaver {
+                    if screensaver.is_none() && after <= idle_for {
+                        tracing::debug!("Idle for {:?}, starting screensaver", after);
+                        deck.suspend_drawing();
+                        let token = shutdown.child_token();
+                        let handle = task::spawn(
+                            "screensaver",
+                            run_screensaver(deck.clone(), kind.clone(), token.clone()),
+                        )?;
+                        screensaver = Some((token, handle));
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn run_screensaver(
+    deck: StreamDeckPlus,
+    kind: Screensaver,
+    token: CancellationToken,
+) -> Result<()> {
+    match kind {
+        #[cfg(feature = "text")]
+        Screensaver::Clock => clock(deck, token).await,
+        Screensaver::Slideshow { images, interval } => {
+            slideshow(deck, images, interval, token).await
+        }
+    }
+}
+
+#[cfg(feature = "text")]
+async fn clock(deck: StreamDeckPlus, token: CancellationToken) -> Result<()> {
+    use crate::streamdeck::{
+        solid_image,
+        text::{font_renderer, TextStyle},
+    };
+
+    let black = solid_image(KEY_SIZE, KEY_SIZE, image::Rgb([0, 0, 0]));
+    for index in 0..KEY_COUNT {
+        deck.overlay_button_image(index, &black).await?;
+    }
+
+    let style = TextStyle::new()
+        .size(60.0)
+        .color(image::Rgb([0x80, 0x80, 0x80]))
+        .centered();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let mut shown = String::new();
+
+    loop {
+        tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            _ = interval.tick() => {
+                // Only redraw when the minute changes
+                let now = chrono::Local::now().format("%H:%M").to_string();
+                if now == shown {
+                    continue;
+                }
+
+                let img = font_renderer()
+                    .lock()
+                    .await
+                    .render_text_styled(LCD_WIDTH, LCD_HEIGHT, &now, &style);
+                deck.overlay_lcd_image(0, 0, &img).await?;
+                shown = now;
+            }
+        }
+    }
+}
+
+async fn slideshow(
+    deck: StreamDeckPlus,
+    images: Vec<RgbImage>,
+    interval: Duration,
+    token: CancellationToken,
+) -> Result<()> {
+    if images.is_empty() {
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(interval);
+    for image in images.iter().cycle() {
+        tokio::select! {
+            _ = token.cancelled() => return Ok(()),
+            _ = interval.tick() => {
+                // Keys are a 4x2 grid, cut the image into tiles for them
+                let grid = imageops::resize(
+                    image,
+                    KEY_SIZE * 4,
+                    KEY_SIZE * 2,
+                    imageops::FilterType::Triangle,
+                );
+                for index in 0..KEY_COUNT {
+                    let x = (index as u32 % 4) * KEY_SIZE;
+                    let y = (index as u32 / 4) * KEY_SIZE;
+                    let tile = imageops::crop_imm(&grid, x, y, KEY_SIZE, KEY_SIZE).to_image();
+                    deck.overlay_button_image(index, &tile).await?;
+                }
+
+                let lcd = imageops::resize(
+                    image,
+                    LCD_WIDTH,
+                    LCD_HEIGHT,
+                    imageops::FilterType::Triangle,
+                );
+                deck.overlay_lcd_image(0, 0, &lcd).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::shutdown::{restore_deck, ExitScreen, ShutdownConfig};
+
+    #[tokio::test]
+    async fn shutting_down_during_the_screensaver_leaves_the_deck_clear() {
+        let deck = StreamDeckPlus::fake();
+        let shutdown = CancellationToken::new();
+        let config = IdleConfig {
+            dim_steps: vec![],
+            screensaver: Some((
+                Duration::from_millis(10),
+                Screensaver::Slideshow {
+                    images: vec![RgbImage::new(8, 8)],
+                    interval: Duration::from_secs(60),
+                },
+            )),
+            swallow_wake_input: true,
+        };
+        let (_idle, task) = spawn_idle(deck.clone(), config, shutdown.clone()).unwrap();
+
+        // The slideshow draws its first image straight away
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while deck.take_written().await.is_empty() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("the screensaver never started");
+
+        shutdown.cancel();
+        task.await.unwrap().unwrap();
+        deck.take_written().await;
+
+        let config = ShutdownConfig {
+            screen: ExitScreen::Blank,
+            brightness: None,
+            ..Default::default()
+        };
+        restore_deck(&deck, &config).await.unwrap();
+
+        // Key images start 0x02 0x07 and then the key, LCD images start 0x02 0x0c
+        let written = deck.take_written().await;
+        let keys: HashSet<u8> = written
+            .iter()
+            .filter(|report| report[..2] == [0x02, 0x07])
+            .map(|report| report[2])
+            .collect();
+        assert_eq!(keys, (0..KEY_COUNT).collect());
+        assert!(written.iter().any(|report| report[..2] == [0x02, 0x0c]));
+    }
+}
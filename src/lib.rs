@@ -0,0 +1,42 @@
+//! Driver and tooling for the Elgato Stream Deck+.
+//!
+//! - [`streamdeck`] talks to the device over HID: keys, LCD strip, encoders and brightness.
+//! - With the `text` feature it also renders text, icons and composed key images.
+//! - With the `runtime` feature, [`app`] runs several apps on one deck and routes input
+//!   to the active one, and [`idle`] dims the deck when nobody is using it.
+//! - With the `audio` feature, [`sound`] follows PulseAudio's state for audio apps.
+//! - With the `media` feature, [`media`] follows and controls MPRIS media players.
+//!
+//! ```no_run
+//! use rust_stream_deck::streamdeck::StreamDeckPlus;
+//!
+//! # async fn run() -> Result<(), rust_stream_deck::streamdeck::DeckError> {
+//! let deck = StreamDeckPlus::connect_exactly_one().await?;
+//! deck.set_brightness(50).await?;
+//! deck.set_button_color(0, image::Rgb([0xFF, 0x00, 0x00])).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+// Re-exported since images are part of the public API
+pub use image;
+
+#[cfg(feature = "runtime")]
+pub mod app;
+#[cfg(feature = "runtime")]
+pub mod apps;
+#[cfg(feature = "runtime")]
+pub mod idle;
+#[cfg(all(feature = "media", unix))]
+pub mod media;
+#[cfg(feature = "runtime")]
+pub mod shutdown;
+#[cfg(all(feature = "audio", unix))]
+pub mod sound;
+pub mod streamdeck;
+pub mod task;
+
+#[cfg(test)]
+mod fixtures;
+#[cfg(all(test, feature = "text"))]
+mod golden;
@@ -1,37 +1,77 @@
-mod app;
-mod streamdeck;
-
 use std::time::Duration;
 
 use anyhow::Result;
-use tokio::signal;
 use tokio::sync::mpsc;
-use tokio::time::sleep;
-
-use crate::app::{spawn_app, Apps};
-use crate::streamdeck::{Input, StreamDeckPlus};
+use tokio_util::sync::CancellationToken;
+
+use rust_stream_deck::{
+    app::Apps,
+    idle::{spawn_idle, IdleConfig, Screensaver},
+    shutdown::{restore_deck, Shutdown, ShutdownConfig},
+    spawn_app,
+    streamdeck::{
+        encoder::{EncoderConfig, EncoderValue},
+        Input, StreamDeckPlus,
+    },
+};
+#[cfg(all(feature = "audio", unix))]
+use rust_stream_deck::{
+    app::{Control, Route},
+    apps::{
+        devices::device_switcher,
+        meters::level_meters,
+        mic::{mic_key, MicKeyConfig},
+        mixer::mixer,
+    },
+    sound::AudioService,
+};
+#[cfg(all(feature = "media", unix))]
+use rust_stream_deck::{apps::media::media, media::MediaService};
+
+async fn app_one(
+    deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    // Count with the first encoder, turning it quickly counts faster
+    let mut count = EncoderValue::new(0, 0.0, -1000.0, 1000.0, EncoderConfig::new().accelerated());
 
-async fn app_one(_deck: StreamDeckPlus, mut inputs: mpsc::UnboundedReceiver<Input>) -> Result<()> {
     loop {
-        let input = inputs.recv().await;
-        tracing::info!("App one got input: {:?}", input);
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            Some(input) = inputs.recv() => {
+                tracing::info!("App one got input: {:?}", input);
+                if let Some(value) = count.handle(&input) {
+                    deck.set_lcd_message(format!("Count: {}", value)).await?;
+                }
+            }
+        }
     }
 }
 
-async fn app_two(_deck: StreamDeckPlus, mut inputs: mpsc::UnboundedReceiver<Input>) -> Result<()> {
+async fn app_two(
+    _deck: StreamDeckPlus,
+    mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
+) -> Result<()> {
     loop {
-        let input = inputs.recv().await;
-        tracing::info!("App two got input: {:?}", input);
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            input = inputs.recv() => tracing::info!("App two got input: {:?}", input),
+        }
     }
 }
 
 async fn app_three(
     _deck: StreamDeckPlus,
     mut inputs: mpsc::UnboundedReceiver<Input>,
+    shutdown: CancellationToken,
 ) -> Result<()> {
     loop {
-        let input = inputs.recv().await;
-        tracing::info!("App three got input: {:?}", input);
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            input = inputs.recv() => tracing::info!("App three got input: {:?}", input),
+        }
     }
 }
 
@@ -48,23 +88,64 @@ async fn main() -> anyhow::Result<()> {
     // Turn it's brightness all the way up
     deck.set_brightness(100).await?;
 
-    // Spin up some apps
-    let mut apps = Apps::new(deck.clone());
+    // Spin up some apps, the first one is up to start with
+    let shutdown = Shutdown::new();
+    let mut apps = Apps::new(deck.clone(), shutdown.token());
+
+    // Volume dials for whatever is playing
+    #[cfg(all(feature = "audio", unix))]
+    let audio = AudioService::start(shutdown.token()).await?;
+    #[cfg(all(feature = "audio", unix))]
+    {
+        spawn_app!(apps, "mixer", mixer, audio.handle());
+        spawn_app!(apps, "devices", device_switcher, audio.handle());
+        spawn_app!(apps, "meters", level_meters, audio.handle());
+
+        // Mute the mic from any app, holding it while muted is push to talk
+        let mic = MicKeyConfig::new(4).push_to_talk(Duration::from_millis(400));
+        spawn_app!(apps, "mic", mic_key, audio.handle(), mic);
+        if let Some(index) = apps.index_of("mic") {
+            apps.set_route(Control::Key(mic.key), Route::App(index))?;
+        }
+    }
+
+    // Controls for whichever media player is playing
+    #[cfg(all(feature = "media", unix))]
+    let media_service = MediaService::start(shutdown.token()).await?;
+    #[cfg(all(feature = "media", unix))]
+    spawn_app!(apps, "media", media, media_service.handle());
+
+    // Examples of simpler apps
     spawn_app!(apps, "app one", app_one);
     spawn_app!(apps, "app two", app_two);
     spawn_app!(apps, "app three", app_three);
 
+    // Long pressing the LCD goes through the apps, apart from the mic key which has no screen
+    let mic = apps.index_of("mic");
+    let order = (0..apps.len())
+        .filter(|index| Some(*index) != mic)
+        .collect();
+    apps.switch_on_long_press(order)?;
+
+    // Dim when left alone, and show a clock after a while
+    let idle_config = IdleConfig {
+        screensaver: Some((Duration::from_secs(10 * 60), Screensaver::Clock)),
+        ..Default::default()
+    };
+    let (idle, idle_task) = spawn_idle(deck.clone(), idle_config, shutdown.token())?;
+    apps.set_idle(idle);
+
     // Route the inputs to the active app
     apps.route()?;
 
-    apps.activate(0)?;
-    sleep(Duration::from_secs(3)).await;
-    apps.activate(1)?;
-    sleep(Duration::from_secs(3)).await;
-    apps.activate(2)?;
+    // Wait for SIGINT or SIGTERM to exit
+    shutdown.wait_for_signal().await?;
 
-    // Wait for SIGINT to exit
-    signal::ctrl_c().await?;
+    // Let the apps finish, then leave the deck clean
+    let config = ShutdownConfig::default();
+    apps.shutdown(config.timeout).await?;
+    idle_task.await??;
+    restore_deck(&deck, &config).await?;
     Ok(())
 
     // Run the apps
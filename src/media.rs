@@ -0,0 +1,386 @@
+//! Media players on the D-Bus session bus, through MPRIS.
+//!
+//! [`MediaService`] follows every player that comes and goes on the bus and picks the one
+//! apps act on, the last one to start playing. Apps get a [`watch`] receiver for the
+//! [`MediaState`] and control the active player through a [`MediaHandle`].
+
+#[cfg(test)]
+mod fake;
+mod mpris;
+mod state;
+
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use futures_lite::StreamExt;
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use zbus::{fdo::DBusProxy, fdo::PropertiesProxy, Connection};
+
+use self::mpris::MprisProxy;
+pub use self::state::{MediaState, PlaybackStatus, Player, Track};
+use crate::task;
+
+/// Controls for the active player.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MediaCommand {
+    PlayPause,
+    Next,
+    Previous,
+    /// Microseconds, negative seeks back.
+    Seek(i64),
+    /// 1.0 is 100%.
+    SetVolume(f64),
+}
+
+/// Follows the media players in the background and publishes their state.
+#[derive(Debug)]
+pub struct MediaService {
+    handle: MediaHandle,
+    task: JoinHandle<Result<()>>,
+}
+
+/// What apps use to follow and control the players.
+#[derive(Debug, Clone)]
+pub struct MediaHandle {
+    state: watch::Receiver<MediaState>,
+    connection: Connection,
+    // The last player commands went to, so it's only connected to again when that changes
+    player: Arc<Mutex<Option<(String, MprisProxy<'static>)>>>,
+}
+
+impl MediaService {
+    /// Starts following the players on the session bus, until `shutdown` is cancelled.
+    pub async fn start(shutdown: CancellationToken) -> Result<Self> {
+        Self::start_on(Connection::session().await?, shutdown).await
+    }
+
+    /// Like [`start`](Self::start), for the players on `connection`'s bus.
+    pub async fn start_on(connection: Connection, shutdown: CancellationToken) -> Result<Self> {
+        let dbus = DBusProxy::new(&connection).await?;
+
+        // Watch for players coming and going before listing them, so none are missed
+        let owners = dbus.receive_name_owner_changed().await?;
+        let names = dbus.list_names().await?;
+
+        let (state_tx, state) = watch::channel(MediaState::default());
+        let (updates_tx, updates) = mpsc::unbounded_channel();
+        let mut service = Service {
+            connection: connection.clone(),
+            state: state_tx,
+            updates: updates_tx,
+            players: HashMap::new(),
+            followers: 0,
+            shutdown,
+        };
+        for name in names {
+            if name.starts_with(mpris::BUS_PREFIX) {
+                service.follow(name.to_string())?;
+            }
+        }
+
+        let task = task::spawn("media service", service.run(owners, updates))?;
+        Ok(Self {
+            handle: MediaHandle {
+                state,
+                connection,
+                player: Arc::default(),
+            },
+            task,
+        })
+    }
+
+    pub fn handle(&self) -> MediaHandle {
+        self.handle.clone()
+    }
+
+    /// Receiver for the current state, marked changed whenever it's updated.
+    pub fn subscribe(&self) -> watch::Receiver<MediaState> {
+        self.handle.subscribe()
+    }
+
+    pub fn state(&self) -> MediaState {
+        self.handle.state()
+    }
+
+    /// Waits for the service to stop, returning why it did.
+    pub async fn join(self) -> Result<()> {
+        self.task.await?
+    }
+}
+
+impl MediaHandle {
+    /// Receiver for the current state, marked changed whenever it's updated.
+    pub fn subscribe(&self) -> watch::Receiver<MediaState> {
+        self.state.clone()
+    }
+
+    pub fn state(&self) -> MediaState {
+        self.state.borrow().clone()
+    }
+
+    /// Runs a command on the active player, the state catches up once the player says so.
+    pub async fn run(&self, command: MediaCommand) -> Result<()> {
+        tracing::debug!("Running {:?}", command);
+
+        let active = self.state.borrow().active.clone();
+        let bus_name = active.ok_or_else(|| anyhow!("No media player"))?;
+        let player = self.player(&bus_name).await?;
+
+        match command {
+            MediaCommand::PlayPause => player.play_pause().await?,
+            MediaCommand::Next => player.next().await?,
+            MediaCommand::Previous => player.previous().await?,
+            MediaCommand::Seek(offset) => player.seek(offset).await?,
+            MediaCommand::SetVolume(volume) => player.set_volume(volume.max(0.0)).await?,
+        }
+        Ok(())
+    }
+
+    async fn player(&self, bus_name: &str) -> Result<MprisProxy<'static>> {
+        let mut player = self.player.lock().await;
+        match &*player {
+            Some((name, proxy)) if name == bus_name => Ok(proxy.clone()),
+            _ => {
+                let proxy = mpris::connect(&self.connection, bus_name).await?;
+                *player = Some((bus_name.to_string(), proxy.clone()));
+                Ok(proxy)
+            }
+        }
+    }
+}
+
+// Tagged with the follower that sent it, so ones from a follower since replaced are ignored
+#[derive(Debug)]
+enum PlayerUpdate {
+    Changed(u64, Player),
+    Gone(u64, String),
+}
+
+struct Service {
+    connection: Connection,
+    state: watch::Sender<MediaState>,
+    updates: mpsc::UnboundedSender<PlayerUpdate>,
+    // A task following each player, cancelled when it leaves the bus
+    players: HashMap<String, (u64, CancellationToken)>,
+    followers: u64,
+    shutdown: CancellationToken,
+}
+
+impl Service {
+    async fn run(
+        mut self,
+        mut owners: zbus::fdo::NameOwnerChangedStream<'static>,
+        mut updates: mpsc::UnboundedReceiver<PlayerUpdate>,
+    ) -> Result<()> {
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return Ok(()),
+                Some(signal) = owners.next() => {
+                    let args = signal.args()?;
+                    let name = args.name().to_string();
+                    if !name.starts_with(mpris::BUS_PREFIX) {
+                        continue;
+                    }
+
+                    // A new owner is a player restarting, so it's followed again either way
+                    self.forget(&name);
+                    if args.new_owner().is_some() {
+                        self.follow(name)?;
+                    }
+                }
+                Some(update) = updates.recv() => match update {
+                    // Followers can send one last update after they're stopped
+                    PlayerUpdate::Changed(follower, player)
+                        if self.is_following(&player.bus_name, follower) =>
+                    {
+                        self.state.send_modify(|state| state.upsert(player));
+                    }
+                    PlayerUpdate::Gone(follower, name) if self.is_following(&name, follower) => {
+                        self.forget(&name)
+                    }
+                    _ => {}
+                },
+            }
+        }
+    }
+
+    fn follow(&mut self, bus_name: String) -> Result<()> {
+        let stop = self.shutdown.child_token();
+        self.followers += 1;
+        let follower = self.followers;
+        self.players
+            .insert(bus_name.clone(), (follower, stop.clone()));
+
+        let connection = self.connection.clone();
+        let updates = self.updates.clone();
+        task::spawn("media player", async move {
+            let followed = follow_player(&connection, &bus_name, follower, &updates, stop).await;
+            if let Err(err) = followed {
+                tracing::debug!("Stopped following {}: {:?}", bus_name, err);
+                let _ = updates.send(PlayerUpdate::Gone(follower, bus_name));
+            }
+        })?;
+        Ok(())
+    }
+
+    fn is_following(&self, bus_name: &str, follower: u64) -> bool {
+        self.players
+            .get(bus_name)
+            .is_some_and(|(current, _)| *current == follower)
+    }
+
+    fn forget(&mut self, bus_name: &str) {
+        if let Some((_, stop)) = self.players.remove(bus_name) {
+            stop.cancel();
+            self.state.send_modify(|state| state.remove(bus_name));
+        }
+    }
+}
+
+// Reads the player again whenever its properties change or it seeks
+async fn follow_player(
+    connection: &Connection,
+    bus_name: &str,
+    follower: u64,
+    updates: &mpsc::UnboundedSender<PlayerUpdate>,
+    stop: CancellationToken,
+) -> Result<()> {
+    let player = mpris::connect(connection, bus_name).await?;
+    let properties = PropertiesProxy::builder(connection)
+        .destination(bus_name.to_string())?
+        .path(mpris::OBJECT_PATH)?
+        .build()
+        .await?;
+    let mut changed = properties.receive_properties_changed().await?;
+    let mut seeked = player.receive_seeked().await?;
+
+    loop {
+        let read = mpris::read_player(&player).await?;
+        if updates.send(PlayerUpdate::Changed(follower, read)).is_err() {
+            return Ok(());
+        }
+
+        tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            Some(_) = changed.next() => {}
+            Some(_) = seeked.next() => {}
+            else => return Err(anyhow!("Signals from {} stopped", bus_name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{fake::*, *};
+
+    // Waits for the next state matching `check`, the service gets there in its own time
+    async fn wait_for(service: &MediaService, check: impl Fn(&MediaState) -> bool) -> MediaState {
+        let mut states = service.subscribe();
+        let state = tokio::time::timeout(Duration::from_secs(5), states.wait_for(check))
+            .await
+            .expect("timed out waiting for the media state")
+            .unwrap();
+        state.clone()
+    }
+
+    fn title(state: &MediaState) -> Option<&str> {
+        state.active()?.track.title.as_deref()
+    }
+
+    #[tokio::test]
+    async fn follows_a_player() {
+        let Some(bus) = PrivateBus::start().await else {
+            return;
+        };
+        let player = FakePlayer::start(&bus, "music").await.unwrap();
+        let service =
+            MediaService::start_on(bus.connect().await.unwrap(), CancellationToken::new())
+                .await
+                .unwrap();
+
+        let state = wait_for(&service, |state| title(state) == Some("Track 1")).await;
+        let active = state.active().unwrap();
+        assert_eq!(active.name(), "music");
+        assert_eq!(active.status, PlaybackStatus::Paused);
+        assert_eq!(active.track.artists, ["Artist"]);
+        assert_eq!(active.track.length, Some(Duration::from_secs(180)));
+
+        // Changes made on the player's side come through too
+        player.set_title("Renamed").await.unwrap();
+        wait_for(&service, |state| title(state) == Some("Renamed")).await;
+    }
+
+    #[tokio::test]
+    async fn controls_the_active_player() {
+        let Some(bus) = PrivateBus::start().await else {
+            return;
+        };
+        let _player = FakePlayer::start(&bus, "music").await.unwrap();
+        let service =
+            MediaService::start_on(bus.connect().await.unwrap(), CancellationToken::new())
+                .await
+                .unwrap();
+        let media = service.handle();
+        wait_for(&service, |state| state.active().is_some()).await;
+
+        media.run(MediaCommand::PlayPause).await.unwrap();
+        wait_for(&service, |state| {
+            state.active().map(|p| p.status) == Some(PlaybackStatus::Playing)
+        })
+        .await;
+
+        media.run(MediaCommand::Next).await.unwrap();
+        wait_for(&service, |state| title(state) == Some("Track 2")).await;
+
+        media.run(MediaCommand::Seek(30_000_000)).await.unwrap();
+        let state = wait_for(&service, |state| {
+            state
+                .active()
+                .is_some_and(|p| p.position >= Duration::from_secs(30))
+        })
+        .await;
+        assert!(state.active().unwrap().position < Duration::from_secs(40));
+
+        media.run(MediaCommand::SetVolume(0.25)).await.unwrap();
+        wait_for(&service, |state| {
+            state.active().map(|p| p.volume) == Some(0.25)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn players_come_and_go() {
+        let Some(bus) = PrivateBus::start().await else {
+            return;
+        };
+        let service =
+            MediaService::start_on(bus.connect().await.unwrap(), CancellationToken::new())
+                .await
+                .unwrap();
+        assert!(service.state().players.is_empty());
+
+        let music = FakePlayer::start(&bus, "music").await.unwrap();
+        wait_for(&service, |state| state.players.len() == 1).await;
+        let video = FakePlayer::start(&bus, "video").await.unwrap();
+        wait_for(&service, |state| state.players.len() == 2).await;
+
+        // Starting to play makes it the active one
+        video.play().await.unwrap();
+        wait_for(&service, |state| {
+            state.active().map(Player::name) == Some("video")
+        })
+        .await;
+
+        drop(video);
+        let state = wait_for(&service, |state| state.players.len() == 1).await;
+        assert_eq!(state.active().map(Player::name), Some("music"));
+        drop(music);
+        wait_for(&service, |state| state.active.is_none()).await;
+    }
+}
@@ -0,0 +1,219 @@
+//! A private D-Bus daemon and a fake MPRIS player for tests.
+//!
+//! Each test gets its own dbus-daemon, so nothing on the desktop's session bus is touched.
+//! Tests are skipped on machines without dbus-daemon, but fail on CI where it should be there.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::{anyhow, Result};
+use zbus::{
+    connection,
+    object_server::{InterfaceRef, SignalContext},
+    zvariant::{ObjectPath, OwnedValue, Value},
+    Connection,
+};
+
+use super::mpris::{BUS_PREFIX, OBJECT_PATH};
+
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    /// Starts a daemon, `None` if dbus-daemon isn't installed and this isn't CI.
+    pub async fn start() -> Option<Self> {
+        let daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut daemon = match daemon {
+            Ok(daemon) => daemon,
+            Err(err) if std::env::var_os("CI").is_some() => {
+                panic!("Could not start dbus-daemon, which CI needs: {}", err)
+            }
+            Err(err) => {
+                eprintln!("Skipping, could not start dbus-daemon: {}", err);
+                return None;
+            }
+        };
+
+        // It prints the address once it's listening
+        let stdout = daemon.stdout.take().unwrap();
+        let address = tokio::task::spawn_blocking(move || {
+            let mut line = String::new();
+            BufReader::new(stdout).read_line(&mut line).map(|_| line)
+        })
+        .await
+        .unwrap()
+        .expect("could not read the dbus-daemon address");
+
+        Some(Self {
+            daemon,
+            address: address.trim().to_string(),
+        })
+    }
+
+    pub async fn connect(&self) -> zbus::Result<Connection> {
+        connection::Builder::address(self.address.as_str())?
+            .build()
+            .await
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+        let _ = self.daemon.wait();
+    }
+}
+
+/// A player on the private bus, it leaves the bus when dropped.
+pub struct FakePlayer {
+    connection: Connection,
+}
+
+impl FakePlayer {
+    /// Starts a paused player on "Track 1", as org.mpris.MediaPlayer2.`name`.
+    pub async fn start(bus: &PrivateBus, name: &str) -> Result<Self> {
+        let connection = connection::Builder::address(bus.address.as_str())?
+            .name(format!("{}{}", BUS_PREFIX, name))?
+            .serve_at(OBJECT_PATH, FakeMpris::default())?
+            .build()
+            .await?;
+        Ok(Self { connection })
+    }
+
+    pub async fn set_title(&self, title: &str) -> Result<()> {
+        let player = self.interface().await?;
+        player.get_mut().await.title = Some(title.to_string());
+        player
+            .get()
+            .await
+            .metadata_changed(player.signal_context())
+            .await?;
+        Ok(())
+    }
+
+    pub async fn play(&self) -> Result<()> {
+        let player = self.interface().await?;
+        player.get_mut().await.status = "Playing";
+        player
+            .get()
+            .await
+            .playback_status_changed(player.signal_context())
+            .await?;
+        Ok(())
+    }
+
+    async fn interface(&self) -> Result<InterfaceRef<FakeMpris>> {
+        self.connection
+            .object_server()
+            .interface::<_, FakeMpris>(OBJECT_PATH)
+            .await
+            .map_err(|err| anyhow!("Fake player is gone: {:?}", err))
+    }
+}
+
+struct FakeMpris {
+    status: &'static str,
+    track: u32,
+    // Overrides the track's own title
+    title: Option<String>,
+    position: i64,
+    volume: f64,
+}
+
+impl Default for FakeMpris {
+    fn default() -> Self {
+        Self {
+            status: "Paused",
+            track: 1,
+            title: None,
+            position: 0,
+            volume: 1.0,
+        }
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl FakeMpris {
+    async fn play_pause(&mut self, #[zbus(signal_context)] context: SignalContext<'_>) {
+        self.status = if self.status == "Playing" {
+            "Paused"
+        } else {
+            "Playing"
+        };
+        let _ = self.playback_status_changed(&context).await;
+    }
+
+    async fn next(&mut self, #[zbus(signal_context)] context: SignalContext<'_>) {
+        self.change_track(self.track + 1, &context).await;
+    }
+
+    async fn previous(&mut self, #[zbus(signal_context)] context: SignalContext<'_>) {
+        self.change_track(self.track.saturating_sub(1).max(1), &context)
+            .await;
+    }
+
+    async fn seek(&mut self, offset: i64, #[zbus(signal_context)] context: SignalContext<'_>) {
+        self.position = (self.position + offset).max(0);
+        let _ = Self::seeked(&context, self.position).await;
+    }
+
+    #[zbus(signal)]
+    async fn seeked(context: &SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        self.status.to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let track_id = ObjectPath::try_from(format!("/track/{}", self.track)).unwrap();
+        let title = self
+            .title
+            .clone()
+            .unwrap_or_else(|| format!("Track {}", self.track));
+        let values: [(&str, Value); 4] = [
+            ("mpris:trackid", track_id.into()),
+            ("xesam:title", title.into()),
+            ("xesam:artist", vec!["Artist"].into()),
+            ("mpris:length", 180_000_000i64.into()),
+        ];
+        values
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.try_into().unwrap()))
+            .collect()
+    }
+
+    #[zbus(property)]
+    fn position(&self) -> i64 {
+        self.position
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        self.volume = volume;
+    }
+}
+
+impl FakeMpris {
+    async fn change_track(&mut self, track: u32, context: &SignalContext<'_>) {
+        self.track = track;
+        self.title = None;
+        self.position = 0;
+        let _ = self.metadata_changed(context).await;
+    }
+}
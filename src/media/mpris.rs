@@ -0,0 +1,163 @@
+//! The MPRIS player interface, and reading a player's state through it.
+//!
+//! <https://specifications.freedesktop.org/mpris-spec/latest/Player_Interface.html>
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use zbus::{
+    proxy::CacheProperties,
+    zvariant::{OwnedValue, Value},
+    Connection,
+};
+
+use super::state::{PlaybackStatus, Player, Track};
+
+/// Every MPRIS player's bus name starts with this.
+pub const BUS_PREFIX: &str = "org.mpris.MediaPlayer2.";
+pub const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+
+#[zbus::proxy(
+    interface = "org.mpris.MediaPlayer2.Player",
+    default_path = "/org/mpris/MediaPlayer2"
+)]
+pub trait Mpris {
+    fn play_pause(&self) -> zbus::Result<()>;
+    fn next(&self) -> zbus::Result<()>;
+    fn previous(&self) -> zbus::Result<()>;
+    /// Microseconds, negative seeks back.
+    fn seek(&self, offset: i64) -> zbus::Result<()>;
+
+    /// Sent when the position jumps, rather than as it plays.
+    #[zbus(signal)]
+    fn seeked(&self, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn metadata(&self) -> zbus::Result<HashMap<String, OwnedValue>>;
+    #[zbus(property)]
+    fn position(&self) -> zbus::Result<i64>;
+    #[zbus(property)]
+    fn volume(&self) -> zbus::Result<f64>;
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) -> zbus::Result<()>;
+}
+
+/// A proxy for the player at `bus_name`.
+pub async fn connect(connection: &Connection, bus_name: &str) -> zbus::Result<MprisProxy<'static>> {
+    // Position is never signalled, so it has to be asked for every time anyway
+    MprisProxy::builder(connection)
+        .destination(bus_name.to_string())?
+        .cache_properties(CacheProperties::No)
+        .build()
+        .await
+}
+
+pub async fn read_player(proxy: &MprisProxy<'_>) -> zbus::Result<Player> {
+    let status = PlaybackStatus::from_mpris(&proxy.playback_status().await?);
+    let track = read_metadata(&proxy.metadata().await?);
+    let read_at = Instant::now();
+
+    // Both are optional, some players don't have them
+    let position = proxy.position().await.unwrap_or_default();
+    let volume = proxy.volume().await.unwrap_or(1.0);
+
+    Ok(Player {
+        bus_name: proxy.inner().destination().to_string(),
+        status,
+        track,
+        volume,
+        position: Duration::from_micros(position.max(0) as u64),
+        read_at,
+    })
+}
+
+/// Picks the track out of a player's Metadata property.
+pub fn read_metadata(metadata: &HashMap<String, OwnedValue>) -> Track {
+    let value = |key: &str| metadata.get(key).map(|value| &**value);
+
+    Track {
+        id: value("mpris:trackid").and_then(string),
+        title: value("xesam:title").and_then(string),
+        artists: value("xesam:artist").map(strings).unwrap_or_default(),
+        album: value("xesam:album").and_then(string),
+        art_url: value("mpris:artUrl").and_then(string),
+        length: value("mpris:length").and_then(micros),
+    }
+}
+
+fn string(value: &Value) -> Option<String> {
+    match value {
+        Value::Str(value) => Some(value.to_string()),
+        Value::ObjectPath(path) => Some(path.to_string()),
+        _ => None,
+    }
+}
+
+// Supposed to be a list, but some players send a single string
+fn strings(value: &Value) -> Vec<String> {
+    match value {
+        Value::Array(array) => array.iter().filter_map(string).collect(),
+        value => string(value).into_iter().collect(),
+    }
+}
+
+// Supposed to be an i64, players get this wrong too
+fn micros(value: &Value) -> Option<Duration> {
+    let micros = match *value {
+        Value::I64(micros) => u64::try_from(micros).ok()?,
+        Value::U64(micros) => micros,
+        Value::I32(micros) => u64::try_from(micros).ok()?,
+        Value::U32(micros) => micros as u64,
+        _ => return None,
+    };
+    Some(Duration::from_micros(micros))
+}
+
+#[cfg(test)]
+mod tests {
+    use zbus::zvariant::ObjectPath;
+
+    use super::*;
+
+    fn owned(value: Value) -> OwnedValue {
+        value.try_into().unwrap()
+    }
+
+    #[test]
+    fn reads_metadata() {
+        let metadata = HashMap::from([
+            (
+                "mpris:trackid".to_string(),
+                owned(ObjectPath::try_from("/track/1").unwrap().into()),
+            ),
+            ("xesam:title".to_string(), owned("Song".into())),
+            ("xesam:artist".to_string(), owned(vec!["One", "Two"].into())),
+            ("mpris:length".to_string(), owned(90_000_000i64.into())),
+        ]);
+
+        let track = read_metadata(&metadata);
+        assert_eq!(track.id.as_deref(), Some("/track/1"));
+        assert_eq!(track.title.as_deref(), Some("Song"));
+        assert_eq!(track.artists, ["One", "Two"]);
+        assert_eq!(track.album, None);
+        assert_eq!(track.length, Some(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn tolerates_sloppy_players() {
+        let metadata = HashMap::from([
+            ("xesam:artist".to_string(), owned("Solo".into())),
+            ("mpris:length".to_string(), owned(5_000_000u64.into())),
+            ("xesam:title".to_string(), owned(3u32.into())),
+        ]);
+
+        let track = read_metadata(&metadata);
+        assert_eq!(track.artists, ["Solo"]);
+        assert_eq!(track.length, Some(Duration::from_secs(5)));
+        assert_eq!(track.title, None);
+    }
+}
@@ -0,0 +1,248 @@
+//! The media players apps see, and which of them is active.
+//!
+//! Nothing in here talks to D-Bus, so picking the active player can be tested on its own.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use super::mpris::BUS_PREFIX;
+
+/// MPRIS' PlaybackStatus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlaybackStatus {
+    Playing,
+    Paused,
+    #[default]
+    Stopped,
+}
+
+impl PlaybackStatus {
+    /// Reads the property's value, anything unknown is stopped.
+    pub fn from_mpris(value: &str) -> Self {
+        match value {
+            "Playing" => PlaybackStatus::Playing,
+            "Paused" => PlaybackStatus::Paused,
+            _ => PlaybackStatus::Stopped,
+        }
+    }
+}
+
+/// What's playing, from the player's metadata.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Track {
+    /// MPRIS track ID, an object path.
+    pub id: Option<String>,
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    /// Usually a file:// URL, streaming players give http ones.
+    pub art_url: Option<String>,
+    pub length: Option<Duration>,
+}
+
+/// A media player on the bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    /// Like org.mpris.MediaPlayer2.spotify.
+    pub bus_name: String,
+    pub status: PlaybackStatus,
+    pub track: Track,
+    /// 1.0 is 100%.
+    pub volume: f64,
+    /// Position when the player was read, see [`position_at`](Self::position_at).
+    pub position: Duration,
+    pub read_at: Instant,
+}
+
+impl Player {
+    /// The part of the bus name after the MPRIS prefix.
+    pub fn name(&self) -> &str {
+        let name = self.bus_name.trim_start_matches(BUS_PREFIX);
+        // Players with several instances add one
+        name.split(".instance").next().unwrap_or(name)
+    }
+
+    /// Where playback is at `now`, players only say when it jumps.
+    pub fn position_at(&self, now: Instant) -> Duration {
+        let mut position = self.position;
+        if self.status == PlaybackStatus::Playing {
+            position += now.saturating_duration_since(self.read_at);
+        }
+        match self.track.length {
+            Some(length) => position.min(length),
+            None => position,
+        }
+    }
+
+    /// 0.0 to 1.0 through the track, `None` if its length isn't known.
+    pub fn progress_at(&self, now: Instant) -> Option<f32> {
+        let length = self.track.length.filter(|length| !length.is_zero())?;
+        Some(self.position_at(now).as_secs_f32() / length.as_secs_f32())
+    }
+}
+
+/// Every player, and the one apps act on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaState {
+    pub players: Vec<Player>,
+    /// Bus name of the active player.
+    pub active: Option<String>,
+    // When each player last started playing
+    started: HashMap<String, Instant>,
+}
+
+impl MediaState {
+    pub fn player(&self, bus_name: &str) -> Option<&Player> {
+        self.players
+            .iter()
+            .find(|player| player.bus_name == bus_name)
+    }
+
+    pub fn active(&self) -> Option<&Player> {
+        self.player(self.active.as_deref()?)
+    }
+
+    pub fn active_mut(&mut self) -> Option<&mut Player> {
+        let active = self.active.as_deref()?;
+        self.players
+            .iter_mut()
+            .find(|player| player.bus_name == active)
+    }
+
+    /// Adds or replaces a player, then picks the active one again.
+    pub fn upsert(&mut self, player: Player) {
+        let was_playing = self
+            .player(&player.bus_name)
+            .is_some_and(|old| old.status == PlaybackStatus::Playing);
+        if player.status == PlaybackStatus::Playing && !was_playing {
+            self.started.insert(player.bus_name.clone(), player.read_at);
+        }
+
+        match self
+            .players
+            .iter_mut()
+            .find(|old| old.bus_name == player.bus_name)
+        {
+            Some(old) => *old = player,
+            None => self.players.push(player),
+        }
+        self.pick_active();
+    }
+
+    pub fn remove(&mut self, bus_name: &str) {
+        self.players.retain(|player| player.bus_name != bus_name);
+        self.started.remove(bus_name);
+        self.pick_active();
+    }
+
+    // Players that are playing win, then whichever started most recently. The active one
+    // stays if nothing beats it, so it doesn't flip between players that never played.
+    fn pick_active(&mut self) {
+        let key = |player: &Player| {
+            (
+                player.status == PlaybackStatus::Playing,
+                self.started.get(&player.bus_name).copied(),
+            )
+        };
+
+        let best = self.players.iter().map(key).max();
+        let current = self.active().map(key);
+        if best.is_some() && current == best {
+            return;
+        }
+
+        self.active = self
+            .players
+            .iter()
+            .find(|player| Some(key(player)) == best)
+            .map(|player| player.bus_name.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn player(name: &str, status: PlaybackStatus, read_at: Instant) -> Player {
+        Player {
+            bus_name: format!("{}{}", BUS_PREFIX, name),
+            status,
+            track: Track::default(),
+            volume: 1.0,
+            position: Duration::ZERO,
+            read_at,
+        }
+    }
+
+    fn active(state: &MediaState) -> Option<&str> {
+        state.active().map(Player::name)
+    }
+
+    #[test]
+    fn follows_whatever_started_playing_last() {
+        use PlaybackStatus::*;
+
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut state = MediaState::default();
+
+        state.upsert(player("music", Stopped, at(0)));
+        state.upsert(player("video", Stopped, at(0)));
+        assert_eq!(active(&state), Some("music"));
+
+        state.upsert(player("video", Playing, at(1)));
+        assert_eq!(active(&state), Some("video"));
+        state.upsert(player("music", Playing, at(2)));
+        assert_eq!(active(&state), Some("music"));
+
+        // Pausing hands over to what's still playing
+        state.upsert(player("music", Paused, at(3)));
+        assert_eq!(active(&state), Some("video"));
+
+        // With nothing playing, the last one to start stays up
+        state.upsert(player("video", Paused, at(4)));
+        assert_eq!(active(&state), Some("music"));
+
+        state.remove("org.mpris.MediaPlayer2.music");
+        assert_eq!(active(&state), Some("video"));
+        state.remove("org.mpris.MediaPlayer2.video");
+        assert_eq!(active(&state), None);
+    }
+
+    #[test]
+    fn position_counts_on_while_playing() {
+        let start = Instant::now();
+        let mut player = player("music", PlaybackStatus::Playing, start);
+        player.position = Duration::from_secs(10);
+        player.track.length = Some(Duration::from_secs(40));
+
+        assert_eq!(
+            player.position_at(start + Duration::from_secs(10)),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            player.progress_at(start + Duration::from_secs(10)),
+            Some(0.5)
+        );
+        assert_eq!(
+            player.position_at(start + Duration::from_secs(60)),
+            Duration::from_secs(40)
+        );
+
+        player.status = PlaybackStatus::Paused;
+        assert_eq!(
+            player.position_at(start + Duration::from_secs(10)),
+            Duration::from_secs(10)
+        );
+    }
+
+    #[test]
+    fn names_drop_the_prefix_and_instance() {
+        let mut player = player("vlc", PlaybackStatus::Stopped, Instant::now());
+        assert_eq!(player.name(), "vlc");
+        player.bus_name = "org.mpris.MediaPlayer2.firefox.instance_1_42".to_string();
+        assert_eq!(player.name(), "firefox");
+    }
+}
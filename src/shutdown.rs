@@ -0,0 +1,106 @@
+//! Shutting down on SIGINT/SIGTERM and leaving the deck in a clean state.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio_util::sync::CancellationToken;
+
+use crate::streamdeck::StreamDeckPlus;
+
+/// What the deck shows after the apps have stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitScreen {
+    /// Leave whatever the apps drew last.
+    Keep,
+    /// Turn every key and the LCD black.
+    Blank,
+    /// Reset the device so it shows the boot logo.
+    Logo,
+}
+
+/// How to shut down.
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// How long apps get to finish after being cancelled before they're aborted.
+    pub timeout: Duration,
+    pub screen: ExitScreen,
+    /// Brightness to leave the deck at, `None` leaves it alone.
+    pub brightness: Option<u8>,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            screen: ExitScreen::Logo,
+            brightness: Some(10),
+        }
+    }
+}
+
+/// Cancels a token once the process is asked to stop.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Token that's cancelled when shutting down, hand child tokens to anything long running.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Starts shutting down without waiting for a signal.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    /// Waits for SIGINT or SIGTERM (or [`trigger`](Self::trigger)), then cancels the token.
+    pub async fn wait_for_signal(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut terminate = signal(SignalKind::terminate())?;
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => {
+                    result?;
+                    tracing::info!("Got SIGINT, shutting down");
+                }
+                _ = terminate.recv() => tracing::info!("Got SIGTERM, shutting down"),
+                _ = self.token.cancelled() => {}
+            }
+        }
+
+        #[cfg(not(unix))]
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                tracing::info!("Got Ctrl-C, shutting down");
+            }
+            _ = self.token.cancelled() => {}
+        }
+
+        self.token.cancel();
+        Ok(())
+    }
+}
+
+/// Puts the deck in its final state once nothing else is drawing on it.
+pub async fn restore_deck(deck: &StreamDeckPlus, config: &ShutdownConfig) -> Result<()> {
+    match config.screen {
+        ExitScreen::Keep => {}
+        ExitScreen::Blank => deck.clear_all().await?,
+        ExitScreen::Logo => deck.reset().await?,
+    }
+
+    if let Some(brightness) = config.brightness {
+        deck.set_brightness(brightness).await?;
+    }
+
+    Ok(())
+}
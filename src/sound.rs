@@ -1,94 +1,684 @@
+//! Audio state from PulseAudio (or PipeWire's PulseAudio server), shared with apps.
+//!
+//! [`AudioService`] keeps an [`AudioState`] up to date in the background. Apps get a
+//! [`watch`] receiver for the whole state or [`AudioEvent`]s for each change, and change it
+//! through an [`AudioHandle`].
+
+#[cfg(test)]
+mod mock;
 mod pulse;
+mod state;
+
+use std::{ffi::CStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use pulseaudio::protocol;
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+
+#[cfg(test)]
+pub(crate) use self::state::fixtures;
+pub use self::{
+    pulse::{PeakStream, PulseAudioSession, ServerAddress},
+    state::{AudioEvent, AudioObject, AudioState, Client, ObjectKind, Sink, Source, Stream},
+};
+use crate::task;
+
+// Events the service follows, the same after reconnecting
+const SUBSCRIPTION_MASK: protocol::SubscriptionMask = protocol::SubscriptionMask::ALL;
+// Waits between reconnection attempts, doubling up to the max
+const RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+// How long commands wait for a lost connection to come back before failing
+const COMMAND_RECONNECT_WAIT: Duration = Duration::from_secs(5);
+
+/// Changes apps can make, volumes are 1.0 for 100%.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioCommand {
+    SetSinkVolume {
+        index: u32,
+        volume: f32,
+    },
+    SetSinkMute {
+        index: u32,
+        mute: bool,
+    },
+    SetSourceVolume {
+        index: u32,
+        volume: f32,
+    },
+    SetSourceMute {
+        index: u32,
+        mute: bool,
+    },
+    SetStreamVolume {
+        index: u32,
+        volume: f32,
+    },
+    SetStreamMute {
+        index: u32,
+        mute: bool,
+    },
+    /// Moves a stream to play on another sink.
+    MoveStream {
+        index: u32,
+        sink: u32,
+    },
+    SetDefaultSink(String),
+    SetDefaultSource(String),
+}
 
-use anyhow::Result;
-use bevy::{prelude::*, tasks::IoTaskPool};
-use crossbeam_channel::unbounded;
-use pulseaudio::protocol::{self, Command, SubscriptionEvent};
+/// Talks to PulseAudio in the background and publishes the audio state.
+///
+/// If the server goes away, like when PipeWire restarts, the service keeps reconnecting
+/// and sends [`AudioEvent::AudioServiceRestarted`] once it's caught up again.
+#[derive(Debug)]
+pub struct AudioService {
+    handle: AudioHandle,
+    task: JoinHandle<Result<()>>,
+}
 
-use crate::sound::pulse::PulseAudioSession;
+/// What apps use to follow and change the audio state.
+#[derive(Debug, Clone)]
+pub struct AudioHandle {
+    state: watch::Receiver<AudioState>,
+    events: broadcast::Sender<AudioEvent>,
+    // Replaced on reconnecting
+    pa: watch::Receiver<PulseAudioSession>,
+}
 
-pub struct SoundPlugin;
+impl AudioService {
+    /// Connects to the server and starts following its state, until `shutdown` is cancelled.
+    pub async fn start(shutdown: CancellationToken) -> Result<Self> {
+        Self::start_at(ServerAddress::from_env()?, shutdown).await
+    }
 
-impl Plugin for SoundPlugin {
-    fn build(&self, app: &mut bevy::prelude::App) {
-        app.add_systems(PreStartup, pulseaudio_init)
-            .add_systems(PreUpdate, pipewire_events);
+    /// Like [`start`](Self::start), for the server at `address`.
+    pub async fn start_at(address: ServerAddress, shutdown: CancellationToken) -> Result<Self> {
+        let (pa, pa_events, initial) = connect(&address).await?;
+        let (state_tx, state) = watch::channel(initial);
+        let (pa_tx, pa) = watch::channel(pa);
+        let (events, _) = broadcast::channel(64);
+
+        let service = Service {
+            address,
+            pa: pa_tx,
+            state: state_tx,
+            events: events.clone(),
+        };
+        let task = task::spawn("audio service", service.run(pa_events, shutdown))?;
+
+        Ok(Self {
+            handle: AudioHandle { state, events, pa },
+            task,
+        })
+    }
+
+    pub fn handle(&self) -> AudioHandle {
+        self.handle.clone()
+    }
+
+    /// Receiver for the current state, marked changed whenever it's updated.
+    pub fn subscribe(&self) -> watch::Receiver<AudioState> {
+        self.handle.subscribe()
+    }
+
+    pub fn state(&self) -> AudioState {
+        self.handle.state()
+    }
+
+    /// Waits for the service to stop, returning why it did.
+    pub async fn join(self) -> Result<()> {
+        self.task.await?
     }
 }
 
-#[derive(Resource)]
-struct SoundActionSender(crossbeam_channel::Sender<Command>);
+impl AudioHandle {
+    /// Receiver for the current state, marked changed whenever it's updated.
+    pub fn subscribe(&self) -> watch::Receiver<AudioState> {
+        self.state.clone()
+    }
 
-#[derive(Debug)]
-enum SoundEvent {}
+    pub fn state(&self) -> AudioState {
+        self.state.borrow().clone()
+    }
 
-#[derive(Debug, Resource)]
-struct SoundEventListener(crossbeam_channel::Receiver<SubscriptionEvent>);
+    /// Each change as it's applied to the state.
+    pub fn events(&self) -> broadcast::Receiver<AudioEvent> {
+        self.events.subscribe()
+    }
 
-#[derive(Debug, Component)]
-pub struct Output;
+    /// Runs a command, the state catches up once the server reports the change.
+    ///
+    /// While the service is reconnecting this waits a few seconds for it, then fails.
+    pub async fn run(&self, command: AudioCommand) -> Result<()> {
+        let pa = self.session().await?;
+        let state = self.state();
+        run_command(&pa, &state, command).await
+    }
 
-#[derive(Debug, Component)]
-pub struct Name(String);
+    /// Starts recording the peak level of a source, see [`PulseAudioSession::record_peaks`].
+    ///
+    /// Streams don't survive the server restarting, they're killed and have to be started
+    /// again.
+    pub async fn record_peaks(&self, source: u32) -> Result<PeakStream> {
+        self.session().await?.record_peaks(source).await
+    }
 
-#[derive(Debug, Component)]
-pub struct Index(usize);
+    // The live session, waiting for the service to reconnect if it's lost
+    async fn session(&self) -> Result<PulseAudioSession> {
+        let mut pa = self.pa.clone();
+        let connected = async {
+            let live = pa.wait_for(|pa| !pa.closed().is_cancelled()).await;
+            live.map(|pa| pa.clone())
+        };
+        match tokio::time::timeout(COMMAND_RECONNECT_WAIT, connected).await {
+            Ok(Ok(pa)) => Ok(pa),
+            Ok(Err(_)) => Err(anyhow!("Audio service stopped")),
+            Err(_) => Err(anyhow!("Still reconnecting to PulseAudio")),
+        }
+    }
+}
+
+// A session, its events and the state when it subscribed
+type Connection = (
+    PulseAudioSession,
+    broadcast::Receiver<protocol::SubscriptionEvent>,
+    AudioState,
+);
+
+// Connects, subscribes and lists the state
+async fn connect(address: &ServerAddress) -> Result<Connection> {
+    let pa = PulseAudioSession::connect(address, env!("CARGO_PKG_NAME").to_string()).await?;
+
+    // Subscribe before the first listing, so nothing changes unnoticed in between
+    let pa_events = pa.subscribe(SUBSCRIPTION_MASK).await?;
+    let state = query_state(&pa).await?;
+    Ok((pa, pa_events, state))
+}
+
+struct Service {
+    address: ServerAddress,
+    pa: watch::Sender<PulseAudioSession>,
+    state: watch::Sender<AudioState>,
+    events: broadcast::Sender<AudioEvent>,
+}
+
+impl Service {
+    async fn run(
+        self,
+        mut pa_events: broadcast::Receiver<protocol::SubscriptionEvent>,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let mut state = self.state.borrow().clone();
+
+        loop {
+            let pa = self.pa.borrow().clone();
+            match self
+                .follow(&pa, &mut pa_events, &mut state, &shutdown)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(err) => tracing::warn!("Lost PulseAudio connection: {:?}", err),
+            }
+
+            // A failed query can leave the connection up, which would keep its reader going
+            pa.close().await;
+
+            let Some((pa, events, new_state)) = self.reconnect(&shutdown).await else {
+                return Ok(());
+            };
+            pa_events = events;
+            self.pa.send_replace(pa);
+
+            // Always marked changed, so apps holding on to streams start them again
+            let changes = state.replace(new_state);
+            self.state.send_replace(state.clone());
+            for change in changes {
+                let _ = self.events.send(change);
+            }
+            let _ = self.events.send(AudioEvent::AudioServiceRestarted);
+            tracing::info!("Reconnected to PulseAudio");
+        }
+    }
+
+    // Applies events until shutdown, or until the connection fails
+    async fn follow(
+        &self,
+        pa: &PulseAudioSession,
+        pa_events: &mut broadcast::Receiver<protocol::SubscriptionEvent>,
+        state: &mut AudioState,
+        shutdown: &CancellationToken,
+    ) -> Result<()> {
+        let closed = pa.closed();
 
-#[derive(Debug, Component)]
-pub struct Volume(f32);
+        loop {
+            let changes = tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = closed.cancelled() => return Err(anyhow!("PulseAudio connection closed")),
+                event = pa_events.recv() => match event {
+                    Ok(event) => apply_event(pa, state, event).await?,
+                    // Missed some, so start over from a full listing
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        tracing::warn!("Missed {} PulseAudio events, listing everything", missed);
+                        state.replace(query_state(pa).await?)
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        return Err(anyhow!("PulseAudio events stopped"))
+                    }
+                },
+            };
+
+            if changes.is_empty() {
+                continue;
+            }
+            self.state.send_replace(state.clone());
+            for change in changes {
+                // Apps only listening to the state is fine
+                let _ = self.events.send(change);
+            }
+        }
+    }
 
-#[derive(Debug, Component)]
-pub struct Muted(bool);
+    // Tries connecting until it works, backing off between attempts, `None` on shutdown
+    async fn reconnect(&self, shutdown: &CancellationToken) -> Option<Connection> {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return None,
+                _ = tokio::time::sleep(delay) => {}
+            }
 
-fn pipewire_events(events_listener: Res<SoundEventListener>) {
-    for event in events_listener.0.try_iter() {
-        trace!("Got SoundEvent: {:?}", event);
+            match connect(&self.address).await {
+                Ok(connected) => return Some(connected),
+                Err(err) => {
+                    tracing::debug!("Could not reconnect to PulseAudio: {:?}", err);
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+        }
     }
 }
 
-fn pulseaudio_init(mut commands: Commands) {
-    let (events_tx, events_rx) = unbounded::<SubscriptionEvent>();
-    let (actions_tx, actions_rx) = unbounded::<Command>();
+// Re-queries whatever the event is about and applies it to `state`
+async fn apply_event(
+    pa: &PulseAudioSession,
+    state: &mut AudioState,
+    event: protocol::SubscriptionEvent,
+) -> Result<Vec<AudioEvent>> {
+    use protocol::{SubscriptionEventFacility as Facility, SubscriptionEventType as EventType};
+    tracing::trace!("Got SubscriptionEvent: {:?}", event);
 
-    commands.spawn((
-        Output,
-        Index(179),
-        Name("Spotify".to_string()),
-        Muted(false),
-        Volume(0.5),
-    ));
+    if event.event_facility == Facility::Server {
+        let server = pa.get_server_info().await?;
+        return Ok(state
+            .set_defaults(
+                server.default_sink_name.as_deref().map(string),
+                server.default_source_name.as_deref().map(string),
+            )
+            .into_iter()
+            .collect());
+    }
 
-    let taskpool = IoTaskPool::get();
+    let kind = match event.event_facility {
+        Facility::Sink => ObjectKind::Sink,
+        Facility::Source => ObjectKind::Source,
+        Facility::SinkInput => ObjectKind::Stream,
+        Facility::Client => ObjectKind::Client,
+        _ => return Ok(vec![]),
+    };
+    let Some(index) = event.index else {
+        return Ok(vec![]);
+    };
 
-    // Subscribe and forward pulseaudio events
-    taskpool
-        .spawn(pulseaudio_subscription_thread(events_tx))
-        .detach();
+    if event.event_type == EventType::Removed {
+        return Ok(state.remove(kind, index).into_iter().collect());
+    }
 
-    // Perform pulseaudio commands
-    taskpool
-        .spawn(pulseaudio_command_thread(actions_rx))
-        .detach();
+    let object = match kind {
+        ObjectKind::Sink => pa
+            .get_sink(index)
+            .await
+            .map(|info| AudioObject::Sink(sink(info))),
+        ObjectKind::Source => pa
+            .get_source(index)
+            .await
+            .map(|info| AudioObject::Source(source(info))),
+        ObjectKind::Stream => pa
+            .get_sink_input(index)
+            .await
+            .map(|info| AudioObject::Stream(stream(info))),
+        ObjectKind::Client => pa
+            .get_client(index)
+            .await
+            .map(|info| AudioObject::Client(client(info))),
+    };
 
-    commands.insert_resource(SoundEventListener(events_rx));
-    commands.insert_resource(SoundActionSender(actions_tx));
+    match object {
+        Ok(object) => Ok(state.upsert(object).into_iter().collect()),
+        // It can be gone again before the query gets there
+        Err(err) if !pa.closed().is_cancelled() => {
+            tracing::debug!("Could not query {:?} {}: {:?}", kind, index, err);
+            Ok(state.remove(kind, index).into_iter().collect())
+        }
+        Err(err) => Err(err),
+    }
 }
 
-async fn pulseaudio_subscription_thread(
-    events_tx: crossbeam_channel::Sender<SubscriptionEvent>,
+async fn run_command(
+    pa: &PulseAudioSession,
+    state: &AudioState,
+    command: AudioCommand,
 ) -> Result<()> {
-    let name = format!("{}-{}", env!("CARGO_PKG_NAME"), "-subscriber");
-    let mut pa = PulseAudioSession::new(name)?;
-    pa.subscribe(protocol::SubscriptionMask::ALL, &events_tx)?;
+    tracing::debug!("Running {:?}", command);
 
-    Ok(())
+    match command {
+        AudioCommand::SetSinkVolume { index, volume } => {
+            let sink = state
+                .sink(index)
+                .ok_or_else(|| anyhow!("No sink {}", index))?;
+            pa.set_sink_volume(index, channel_volume(sink.channels, volume))
+                .await
+        }
+        AudioCommand::SetSinkMute { index, mute } => pa.set_sink_mute(index, mute).await,
+        AudioCommand::SetSourceVolume { index, volume } => {
+            let source = state
+                .source(index)
+                .ok_or_else(|| anyhow!("No source {}", index))?;
+            pa.set_source_volume(index, channel_volume(source.channels, volume))
+                .await
+        }
+        AudioCommand::SetSourceMute { index, mute } => pa.set_source_mute(index, mute).await,
+        AudioCommand::SetStreamVolume { index, volume } => {
+            let stream = state
+                .stream(index)
+                .ok_or_else(|| anyhow!("No stream {}", index))?;
+            pa.set_sink_input_volume(index, channel_volume(stream.channels, volume))
+                .await
+        }
+        AudioCommand::SetStreamMute { index, mute } => pa.set_sink_input_mute(index, mute).await,
+        AudioCommand::MoveStream { index, sink } => pa.move_sink_input(index, sink).await,
+        AudioCommand::SetDefaultSink(name) => pa.set_default_sink(&name).await,
+        AudioCommand::SetDefaultSource(name) => pa.set_default_source(&name).await,
+    }
 }
 
-async fn pulseaudio_command_thread(
-    _actions_rx: crossbeam_channel::Receiver<Command>,
-) -> Result<()> {
-    let name = format!("{}-{}", env!("CARGO_PKG_NAME"), "-action");
-    let mut pa = PulseAudioSession::new(name)?;
+async fn query_state(pa: &PulseAudioSession) -> Result<AudioState> {
+    let server = pa.get_server_info().await?;
+
+    Ok(AudioState {
+        sinks: pa.get_sinks().await?.into_iter().map(sink).collect(),
+        sources: pa.get_sources().await?.into_iter().map(source).collect(),
+        streams: pa
+            .get_sink_inputs()
+            .await?
+            .into_iter()
+            .map(stream)
+            .collect(),
+        clients: pa.get_clients().await?.into_iter().map(client).collect(),
+        default_sink: server.default_sink_name.as_deref().map(string),
+        default_source: server.default_source_name.as_deref().map(string),
+    })
+}
+
+fn sink(info: protocol::SinkInfo) -> Sink {
+    Sink {
+        index: info.index,
+        name: string(&info.name),
+        description: info.description.as_deref().map_or_else(String::new, string),
+        volume: volume(&info.cvolume),
+        channels: info.cvolume.channels().len(),
+        muted: info.muted,
+        icon_name: prop(&info.props, protocol::Prop::DeviceIconName),
+        form_factor: prop(&info.props, protocol::Prop::DeviceFormFactor),
+    }
+}
+
+fn source(info: protocol::SourceInfo) -> Source {
+    Source {
+        index: info.index,
+        name: string(&info.name),
+        description: info.description.as_deref().map_or_else(String::new, string),
+        volume: volume(&info.cvolume),
+        channels: info.cvolume.channels().len(),
+        muted: info.muted,
+        icon_name: prop(&info.props, protocol::Prop::DeviceIconName),
+        form_factor: prop(&info.props, protocol::Prop::DeviceFormFactor),
+        monitor_of: info.monitor_of_sink_index,
+    }
+}
+
+fn stream(info: protocol::SinkInputInfo) -> Stream {
+    Stream {
+        index: info.index,
+        name: string(&info.name),
+        client: info.client_index,
+        app_name: prop(&info.props, protocol::Prop::ApplicationName),
+        icon_name: prop(&info.props, protocol::Prop::ApplicationIconName),
+        sink: info.sink_index,
+        volume: volume(&info.cvolume),
+        channels: info.cvolume.channels().len(),
+        muted: info.muted,
+    }
+}
+
+fn client(info: protocol::ClientInfo) -> Client {
+    Client {
+        index: info.index,
+        name: string(&info.name),
+    }
+}
+
+fn string(value: &CStr) -> String {
+    value.to_string_lossy().into_owned()
+}
+
+// Property values are NUL terminated strings
+fn prop(props: &protocol::Props, prop: protocol::Prop) -> Option<String> {
+    let value = String::from_utf8_lossy(props.get(prop)?);
+    Some(value.trim_end_matches('\0').to_string())
+}
 
-    Ok(())
+// PulseAudio volumes are per channel, with NORM as 100%
+fn volume(volume: &protocol::ChannelVolume) -> f32 {
+    let channels = volume.channels();
+    if channels.is_empty() {
+        return 0.0;
+    }
+
+    let norm = protocol::Volume::NORM.as_u32() as f32;
+    let total: f32 = channels.iter().map(|v| v.as_u32() as f32 / norm).sum();
+    total / channels.len() as f32
+}
+
+// The same volume on every channel
+fn channel_volume(channels: usize, volume: f32) -> protocol::ChannelVolume {
+    let norm = protocol::Volume::NORM.as_u32() as f32;
+    let value = protocol::Volume::from_u32_clamped((volume.max(0.0) * norm).round() as u32);
+
+    let mut channel_volume = protocol::ChannelVolume::empty();
+    for _ in 0..channels.max(1) {
+        channel_volume.push(value);
+    }
+    channel_volume
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{mock::*, *};
+
+    async fn start(state: MockState) -> (MockServer, AudioService) {
+        let server = MockServer::start(state).await.unwrap();
+        let service = AudioService::start_at(server.address(), CancellationToken::new())
+            .await
+            .unwrap();
+        (server, service)
+    }
+
+    fn mock_state() -> MockState {
+        let mut state = MockState {
+            sinks: vec![sink_info(0, "speakers")],
+            sources: vec![
+                source_info(1, "speakers.monitor", Some(0)),
+                source_info(2, "mic", None),
+            ],
+            sink_inputs: vec![sink_input_info(7, "music", 0)],
+            clients: vec![client_info(3, "player")],
+            ..Default::default()
+        };
+        state.server.default_sink_name = Some(std::ffi::CString::new("speakers").unwrap());
+        state.sink_inputs[0].client_index = Some(3);
+        state
+    }
+
+    // Waits for the next state matching `check`, the service gets there in its own time
+    async fn wait_for(service: &AudioService, check: impl Fn(&AudioState) -> bool) -> AudioState {
+        let mut states = service.subscribe();
+        let state = tokio::time::timeout(Duration::from_secs(5), states.wait_for(check))
+            .await
+            .expect("timed out waiting for the audio state")
+            .unwrap();
+        state.clone()
+    }
+
+    #[tokio::test]
+    async fn lists_the_server_state() {
+        let (_server, service) = start(mock_state()).await;
+        let state = service.state();
+
+        assert_eq!(state.default_sink().map(|s| s.index), Some(0));
+        assert_eq!(state.sources.len(), 2);
+        assert_eq!(state.source(1).and_then(|s| s.monitor_of), Some(0));
+
+        let stream = state.stream(7).unwrap();
+        assert_eq!((stream.sink, stream.volume, stream.channels), (0, 1.0, 2));
+        assert_eq!(state.stream_label(stream), "player");
+    }
+
+    #[tokio::test]
+    async fn commands_reach_the_server() {
+        let (server, service) = start(mock_state()).await;
+        let audio = service.handle();
+
+        audio
+            .run(AudioCommand::SetStreamVolume {
+                index: 7,
+                volume: 0.5,
+            })
+            .await
+            .unwrap();
+        audio
+            .run(AudioCommand::SetSourceMute {
+                index: 2,
+                mute: true,
+            })
+            .await
+            .unwrap();
+        audio
+            .run(AudioCommand::SetDefaultSource("mic".to_string()))
+            .await
+            .unwrap();
+
+        let mock = server.state();
+        assert_eq!(volume(&mock.sink_inputs[0].cvolume), 0.5);
+        assert!(mock.sources[1].muted);
+
+        // The server's events bring the state up to date
+        let state = wait_for(&service, |state| {
+            state.default_source.is_some() && state.source(2).is_some_and(|s| s.muted)
+        })
+        .await;
+        assert_eq!(state.stream(7).map(|s| s.volume), Some(0.5));
+        assert_eq!(state.default_source().map(|s| s.index), Some(2));
+    }
+
+    #[tokio::test]
+    async fn follows_scripted_events() {
+        let (server, service) = start(mock_state()).await;
+        let mut events = service.handle().events();
+
+        server.add_sink_input(sink_input_info(8, "video", 0));
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, AudioEvent::Added(AudioObject::Stream(ref s)) if s.index == 8));
+
+        server.remove_sink_input(7);
+        wait_for(&service, |state| state.stream(7).is_none()).await;
+        assert_eq!(
+            events.recv().await.unwrap(),
+            AudioEvent::Removed(ObjectKind::Stream, 7)
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_a_restart() {
+        let (server, service) = start(mock_state()).await;
+        let audio = service.handle();
+        let mut events = audio.events();
+
+        // Changed while nobody's connected, so only the new listing can catch it
+        server.drop_clients();
+        server.update(|state| state.sink_inputs.push(sink_input_info(9, "call", 0)));
+
+        let restarted = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match events.recv().await.unwrap() {
+                    AudioEvent::AudioServiceRestarted => break,
+                    event => assert!(
+                        matches!(event, AudioEvent::Added(AudioObject::Stream(ref s)) if s.index == 9)
+                    ),
+                }
+            }
+        });
+        restarted.await.expect("never reconnected");
+        assert!(audio.state().stream(9).is_some());
+
+        // Commands go over the new connection
+        audio
+            .run(AudioCommand::SetStreamMute {
+                index: 9,
+                mute: true,
+            })
+            .await
+            .unwrap();
+        assert!(server.state().sink_inputs[1].muted);
+    }
+
+    #[tokio::test]
+    async fn commands_wait_for_the_reconnection() {
+        let (server, service) = start(mock_state()).await;
+        let audio = service.handle();
+
+        let closed = audio.pa.borrow().closed();
+        server.drop_clients();
+        closed.cancelled().await;
+
+        audio
+            .run(AudioCommand::SetStreamMute {
+                index: 7,
+                mute: true,
+            })
+            .await
+            .unwrap();
+        assert!(server.state().sink_inputs[0].muted);
+    }
+
+    #[tokio::test]
+    async fn wrong_cookies_are_refused() {
+        let server = MockServer::start(MockState::default()).await.unwrap();
+        let address = ServerAddress {
+            cookie: b"nope".to_vec(),
+            ..server.address()
+        };
+        assert!(PulseAudioSession::connect(&address, "test".to_string())
+            .await
+            .is_err());
+    }
 }
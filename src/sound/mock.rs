@@ -0,0 +1,495 @@
+//! An in-process PulseAudio server for tests.
+//!
+//! Speaks enough of the native protocol over a Unix socket in the temp dir for the session
+//! and audio service: auth, listing and querying objects, subscribing, and the volume,
+//! mute, move and default commands. Objects are whatever the test scripts, and changes
+//! send subscription events like the real server does.
+
+use std::{
+    io::Cursor,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, Result};
+use pulseaudio::protocol::{
+    self, SubscriptionEventFacility as Facility, SubscriptionEventType as EventType,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{unix::OwnedWriteHalf, UnixListener, UnixStream},
+    sync::broadcast,
+};
+use tokio_util::sync::CancellationToken;
+
+use super::{channel_volume, ServerAddress};
+use crate::task;
+
+const DESCRIPTOR_LENGTH: usize = 20;
+const CONTROL_CHANNEL: u32 = u32::MAX;
+// Events aren't answers to anything, so they go out with this as their sequence number
+const EVENT_SEQUENCE: u32 = u32::MAX;
+const COOKIE: &[u8] = b"mock cookie";
+
+/// The objects the server has.
+#[derive(Debug, Clone, Default)]
+pub struct MockState {
+    pub server: protocol::ServerInfo,
+    pub sinks: Vec<protocol::SinkInfo>,
+    pub sources: Vec<protocol::SourceInfo>,
+    pub sink_inputs: Vec<protocol::SinkInputInfo>,
+    pub clients: Vec<protocol::ClientInfo>,
+}
+
+#[derive(Debug)]
+pub struct MockServer {
+    address: ServerAddress,
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<protocol::SubscriptionEvent>,
+    // Cancelling drops every client, like the server going away
+    stop: CancellationToken,
+    // Replaced to drop the clients connected so far but keep listening
+    clients: Arc<Mutex<CancellationToken>>,
+}
+
+impl MockServer {
+    pub async fn start(state: MockState) -> Result<Self> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let socket_path = std::env::temp_dir().join(format!(
+            "rust-stream-deck-pulse-{}-{}.sock",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&socket_path);
+        let listener = UnixListener::bind(&socket_path)?;
+
+        let (events, _) = broadcast::channel(64);
+        let stop = CancellationToken::new();
+        let server = Self {
+            address: ServerAddress {
+                socket_path,
+                cookie: COOKIE.to_vec(),
+            },
+            state: Arc::new(Mutex::new(state)),
+            events,
+            clients: Arc::new(Mutex::new(stop.child_token())),
+            stop,
+        };
+        task::spawn(
+            "mock pulseaudio",
+            accept(
+                listener,
+                server.state.clone(),
+                server.events.clone(),
+                server.stop.clone(),
+                server.clients.clone(),
+            ),
+        )?;
+        Ok(server)
+    }
+
+    /// Where to connect, with the cookie the server wants.
+    pub fn address(&self) -> ServerAddress {
+        self.address.clone()
+    }
+
+    pub fn state(&self) -> MockState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Changes the objects without telling anyone, see [`emit`](Self::emit).
+    pub fn update(&self, update: impl FnOnce(&mut MockState)) {
+        update(&mut self.state.lock().unwrap());
+    }
+
+    /// Sends a subscription event to every subscribed client.
+    pub fn emit(&self, facility: Facility, event_type: EventType, index: Option<u32>) {
+        let _ = self.events.send(event(facility, event_type, index));
+    }
+
+    pub fn add_sink_input(&self, info: protocol::SinkInputInfo) {
+        let index = info.index;
+        self.update(|state| state.sink_inputs.push(info));
+        self.emit(Facility::SinkInput, EventType::New, Some(index));
+    }
+
+    pub fn remove_sink_input(&self, index: u32) {
+        self.update(|state| state.sink_inputs.retain(|info| info.index != index));
+        self.emit(Facility::SinkInput, EventType::Removed, Some(index));
+    }
+
+    /// Hangs up on everyone connected, like the server restarting.
+    pub fn drop_clients(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        clients.cancel();
+        *clients = self.stop.child_token();
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.cancel();
+        let _ = std::fs::remove_file(&self.address.socket_path);
+    }
+}
+
+pub fn sink_info(index: u32, name: &str) -> protocol::SinkInfo {
+    protocol::SinkInfo {
+        index,
+        name: cstring(name),
+        description: Some(cstring(name)),
+        cvolume: channel_volume(2, 1.0),
+        ..Default::default()
+    }
+}
+
+pub fn source_info(index: u32, name: &str, monitor_of: Option<u32>) -> protocol::SourceInfo {
+    protocol::SourceInfo {
+        index,
+        name: cstring(name),
+        description: Some(cstring(name)),
+        cvolume: channel_volume(2, 1.0),
+        monitor_of_sink_index: monitor_of,
+        ..Default::default()
+    }
+}
+
+pub fn sink_input_info(index: u32, name: &str, sink: u32) -> protocol::SinkInputInfo {
+    protocol::SinkInputInfo {
+        index,
+        name: cstring(name),
+        sink_index: sink,
+        cvolume: channel_volume(2, 1.0),
+        ..Default::default()
+    }
+}
+
+pub fn client_info(index: u32, name: &str) -> protocol::ClientInfo {
+    protocol::ClientInfo {
+        index,
+        name: cstring(name),
+        ..Default::default()
+    }
+}
+
+fn cstring(value: &str) -> std::ffi::CString {
+    std::ffi::CString::new(value).unwrap()
+}
+
+fn event(
+    facility: Facility,
+    event_type: EventType,
+    index: Option<u32>,
+) -> protocol::SubscriptionEvent {
+    protocol::SubscriptionEvent {
+        event_facility: facility,
+        event_type,
+        index,
+    }
+}
+
+async fn accept(
+    listener: UnixListener,
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<protocol::SubscriptionEvent>,
+    stop: CancellationToken,
+    clients: Arc<Mutex<CancellationToken>>,
+) -> Result<()> {
+    loop {
+        let socket = tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            socket = listener.accept() => socket?.0,
+        };
+
+        let client = Client {
+            state: state.clone(),
+            events: events.clone(),
+            stop: clients.lock().unwrap().clone(),
+        };
+        task::spawn("mock pulseaudio client", async move {
+            if let Err(err) = client.serve(socket).await {
+                tracing::debug!("Mock client gone: {:?}", err);
+            }
+        })?;
+    }
+}
+
+struct Client {
+    state: Arc<Mutex<MockState>>,
+    events: broadcast::Sender<protocol::SubscriptionEvent>,
+    stop: CancellationToken,
+}
+
+impl Client {
+    async fn serve(self, socket: UnixStream) -> Result<()> {
+        let (mut reader, writer) = socket.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let version = protocol::MAX_VERSION;
+
+        loop {
+            let frame = tokio::select! {
+                _ = self.stop.cancelled() => return Ok(()),
+                frame = read_frame(&mut reader) => frame?,
+            };
+            let (sequence, command) =
+                protocol::read_command_message(&mut Cursor::new(frame), version)?;
+
+            // Every event goes to every subscriber, whatever mask they gave
+            if let protocol::Command::Subscribe(_) = command {
+                let events = self.events.subscribe();
+                task::spawn(
+                    "mock pulseaudio events",
+                    forward_events(events, writer.clone(), self.stop.clone()),
+                )?;
+            }
+
+            let (answer, event) = self.answer(sequence, command, version)?;
+            writer.lock().await.write_all(&answer).await?;
+            if let Some(event) = event {
+                let _ = self.events.send(event);
+            }
+        }
+    }
+
+    // The bytes to send back, and the event the change makes if there is one
+    fn answer(
+        &self,
+        sequence: u32,
+        command: protocol::Command,
+        version: u16,
+    ) -> Result<(Vec<u8>, Option<protocol::SubscriptionEvent>)> {
+        use protocol::Command;
+
+        let mut buffer = Vec::new();
+        let mut state = self.state.lock().unwrap();
+        let reply =
+            |buffer: &mut Vec<u8>, reply: &dyn Reply| reply.write(buffer, sequence, version);
+
+        let event = match command {
+            Command::Auth(params) if params.cookie != COOKIE => {
+                write_error(&mut buffer, sequence, protocol::PulseError::AccessDenied)?;
+                None
+            }
+            Command::Auth(_) => {
+                reply(
+                    &mut buffer,
+                    &protocol::AuthReply {
+                        version: protocol::MAX_VERSION,
+                        ..Default::default()
+                    },
+                )?;
+                None
+            }
+            Command::SetClientName(_) => {
+                reply(&mut buffer, &protocol::SetClientNameReply { client_id: 0 })?;
+                None
+            }
+            Command::Subscribe(_) => {
+                protocol::write_ack_message(&mut buffer, sequence)?;
+                None
+            }
+            Command::GetServerInfo => {
+                reply(&mut buffer, &state.server)?;
+                None
+            }
+            Command::GetSinkInfoList => {
+                reply(&mut buffer, &state.sinks)?;
+                None
+            }
+            Command::GetSourceInfoList => {
+                reply(&mut buffer, &state.sources)?;
+                None
+            }
+            Command::GetSinkInputInfoList => {
+                reply(&mut buffer, &state.sink_inputs)?;
+                None
+            }
+            Command::GetClientInfoList => {
+                reply(&mut buffer, &state.clients)?;
+                None
+            }
+            Command::GetSinkInfo(params) => {
+                match state
+                    .sinks
+                    .iter()
+                    .find(|info| Some(info.index) == params.index)
+                {
+                    Some(info) => reply(&mut buffer, info)?,
+                    None => write_error(&mut buffer, sequence, protocol::PulseError::NoEntity)?,
+                }
+                None
+            }
+            Command::GetSourceInfo(params) => {
+                match state
+                    .sources
+                    .iter()
+                    .find(|info| Some(info.index) == params.index)
+                {
+                    Some(info) => reply(&mut buffer, info)?,
+                    None => write_error(&mut buffer, sequence, protocol::PulseError::NoEntity)?,
+                }
+                None
+            }
+            Command::GetSinkInputInfo(index) => {
+                match state.sink_inputs.iter().find(|info| info.index == index) {
+                    Some(info) => reply(&mut buffer, info)?,
+                    None => write_error(&mut buffer, sequence, protocol::PulseError::NoEntity)?,
+                }
+                None
+            }
+            Command::GetClientInfo(index) => {
+                match state.clients.iter().find(|info| info.index == index) {
+                    Some(info) => reply(&mut buffer, info)?,
+                    None => write_error(&mut buffer, sequence, protocol::PulseError::NoEntity)?,
+                }
+                None
+            }
+            command => {
+                let event = change(&mut state, command);
+                match event {
+                    Some(_) => protocol::write_ack_message(&mut buffer, sequence)?,
+                    None => write_error(&mut buffer, sequence, protocol::PulseError::NoEntity)?,
+                }
+                event
+            }
+        };
+        Ok((buffer, event))
+    }
+}
+
+// Applies a command that changes something, `None` if it's unknown or about nothing
+fn change(
+    state: &mut MockState,
+    command: protocol::Command,
+) -> Option<protocol::SubscriptionEvent> {
+    use protocol::Command;
+
+    let (facility, index) = match command {
+        Command::SetSinkVolume(params) => {
+            let sink = find_sink(state, params.device_index)?;
+            sink.cvolume = params.volume;
+            (Facility::Sink, sink.index)
+        }
+        Command::SetSinkMute(params) => {
+            let sink = find_sink(state, params.device_index)?;
+            sink.muted = params.mute;
+            (Facility::Sink, sink.index)
+        }
+        Command::SetSourceVolume(params) => {
+            let source = find_source(state, params.device_index)?;
+            source.cvolume = params.volume;
+            (Facility::Source, source.index)
+        }
+        Command::SetSourceMute(params) => {
+            let source = find_source(state, params.device_index)?;
+            source.muted = params.mute;
+            (Facility::Source, source.index)
+        }
+        Command::SetSinkInputVolume(params) => {
+            let input = find_sink_input(state, params.index)?;
+            input.cvolume = params.volume;
+            (Facility::SinkInput, input.index)
+        }
+        Command::SetSinkInputMute(params) => {
+            let input = find_sink_input(state, params.index)?;
+            input.muted = params.mute;
+            (Facility::SinkInput, input.index)
+        }
+        Command::MoveSinkInput(params) => {
+            let sink = find_sink(state, params.device_index)?.index;
+            let input = find_sink_input(state, params.index)?;
+            input.sink_index = sink;
+            (Facility::SinkInput, input.index)
+        }
+        Command::SetDefaultSink(name) => {
+            state.server.default_sink_name = name;
+            return Some(event(Facility::Server, EventType::Changed, None));
+        }
+        Command::SetDefaultSource(name) => {
+            state.server.default_source_name = name;
+            return Some(event(Facility::Server, EventType::Changed, None));
+        }
+        command => {
+            tracing::debug!("Mock server can't handle {:?}", command);
+            return None;
+        }
+    };
+    Some(event(facility, EventType::Changed, Some(index)))
+}
+
+fn find_sink(state: &mut MockState, index: Option<u32>) -> Option<&mut protocol::SinkInfo> {
+    state
+        .sinks
+        .iter_mut()
+        .find(|info| Some(info.index) == index)
+}
+
+fn find_source(state: &mut MockState, index: Option<u32>) -> Option<&mut protocol::SourceInfo> {
+    state
+        .sources
+        .iter_mut()
+        .find(|info| Some(info.index) == index)
+}
+
+fn find_sink_input(state: &mut MockState, index: u32) -> Option<&mut protocol::SinkInputInfo> {
+    state
+        .sink_inputs
+        .iter_mut()
+        .find(|info| info.index == index)
+}
+
+// Replies are generic over their type, this lets one closure write any of them
+trait Reply {
+    fn write(&self, buffer: &mut Vec<u8>, sequence: u32, version: u16) -> Result<()>;
+}
+
+impl<R: protocol::CommandReply> Reply for R {
+    fn write(&self, buffer: &mut Vec<u8>, sequence: u32, version: u16) -> Result<()> {
+        protocol::write_reply_message(buffer, sequence, self, version)?;
+        Ok(())
+    }
+}
+
+fn write_error(buffer: &mut Vec<u8>, sequence: u32, error: protocol::PulseError) -> Result<()> {
+    protocol::write_error(buffer, sequence, &error)?;
+    Ok(())
+}
+
+async fn forward_events(
+    mut events: broadcast::Receiver<protocol::SubscriptionEvent>,
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    stop: CancellationToken,
+) -> Result<()> {
+    loop {
+        let event = tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            event = events.recv() => event?,
+        };
+
+        let mut buffer = Vec::new();
+        protocol::write_command_message(
+            &mut buffer,
+            EVENT_SEQUENCE,
+            protocol::Command::SubscribeEvent(event),
+            protocol::MAX_VERSION,
+        )?;
+        writer.lock().await.write_all(&buffer).await?;
+    }
+}
+
+async fn read_frame(reader: &mut (impl AsyncReadExt + Unpin)) -> Result<Vec<u8>> {
+    let mut frame = vec![0; DESCRIPTOR_LENGTH];
+    reader.read_exact(&mut frame).await?;
+
+    let length = u32::from_be_bytes(frame[0..4].try_into()?) as usize;
+    let channel = u32::from_be_bytes(frame[4..8].try_into()?);
+    if channel != CONTROL_CHANNEL {
+        return Err(anyhow!("Mock server doesn't take stream data"));
+    }
+
+    frame.resize(DESCRIPTOR_LENGTH + length, 0);
+    reader.read_exact(&mut frame[DESCRIPTOR_LENGTH..]).await?;
+    Ok(frame)
+}
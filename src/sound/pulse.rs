@@ -1,138 +1,569 @@
-use std::{ffi::CString, os::unix::net::UnixStream};
+//! A PulseAudio connection shared by everything that talks to the server.
+//!
+//! One task reads every frame off the socket: replies go to whoever is waiting on their
+//! sequence number and subscription events go out on a broadcast channel, so commands and
+//! events share the connection. Stream data comes in on its own channels, which only peak
+//! streams are opened for.
 
-use anyhow::{anyhow, bail, ensure, Result};
-use bevy::prelude::*;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    io::Cursor,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{anyhow, ensure, Result};
 use pulseaudio::protocol;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    sync::{broadcast, oneshot},
+};
+use tokio_util::sync::CancellationToken;
 
-#[derive(Debug)]
+use crate::task;
+
+// Frames start with length, channel, offset (two words) and flags
+const DESCRIPTOR_LENGTH: usize = 20;
+// The largest frame the server sends, anything bigger means the stream is corrupt
+const FRAME_SIZE_MAX_ALLOW: usize = 16 * 1024 * 1024;
+// Channel for control packets, anything else is stream data
+const CONTROL_CHANNEL: u32 = u32::MAX;
+// Commands the server answers requests with
+const COMMAND_ERROR: u32 = 0;
+const COMMAND_REPLY: u32 = 2;
+// Peak streams get one float a fragment, this many times a second
+const PEAK_RATE: u32 = 25;
+
+#[derive(Debug, Clone)]
 pub struct PulseAudioSession {
-    sequence: u32,
-    socket: std::io::BufReader<UnixStream>,
-    protocol_version: u16,
+    inner: Arc<Inner>,
 }
 
-impl PulseAudioSession {
-    pub fn new(client_name: String) -> Result<Self> {
-        // Connect to the PulseAudio socket
+#[derive(Debug)]
+struct Inner {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    sequence: AtomicU32,
+    protocol_version: AtomicU16,
+    // Replies not received yet, by sequence number
+    pending: Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>,
+    events: broadcast::Sender<protocol::SubscriptionEvent>,
+    // Open peak streams by channel
+    peaks: Mutex<HashMap<u32, Arc<PeakLevel>>>,
+    closed: CancellationToken,
+}
+
+#[derive(Debug, Default)]
+struct PeakLevel {
+    // Highest peak since it was last taken, as f32 bits
+    peak: AtomicU32,
+    killed: AtomicBool,
+}
+
+/// Where a server listens and the cookie it wants.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerAddress {
+    pub socket_path: PathBuf,
+    pub cookie: Vec<u8>,
+}
+
+impl ServerAddress {
+    /// The server PulseAudio clients would use, from the environment.
+    pub fn from_env() -> Result<Self> {
         let socket_path =
             pulseaudio::socket_path_from_env().ok_or(anyhow!("PulseAudio not available"))?;
-        let mut sock = std::io::BufReader::new(UnixStream::connect(socket_path)?);
 
-        // Find the Auth Cookie
+        // Servers that don't check the cookie take an empty one
         let cookie = pulseaudio::cookie_path_from_env()
             .and_then(|path| std::fs::read(path).ok())
             .unwrap_or_default();
+        Ok(Self {
+            socket_path,
+            cookie,
+        })
+    }
+}
+
+impl PulseAudioSession {
+    /// Connects to the server from the environment.
+    pub async fn new(client_name: String) -> Result<Self> {
+        Self::connect(&ServerAddress::from_env()?, client_name).await
+    }
+
+    pub async fn connect(address: &ServerAddress, client_name: String) -> Result<Self> {
+        let socket = UnixStream::connect(&address.socket_path).await?;
+        let (reader, writer) = socket.into_split();
+
+        let (events, _) = broadcast::channel(64);
+        let session = Self {
+            inner: Arc::new(Inner {
+                writer: tokio::sync::Mutex::new(writer),
+                sequence: AtomicU32::new(0),
+                protocol_version: AtomicU16::new(protocol::MAX_VERSION),
+                pending: Mutex::new(HashMap::new()),
+                events,
+                peaks: Mutex::new(HashMap::new()),
+                closed: CancellationToken::new(),
+            }),
+        };
+        task::spawn(
+            "pulseaudio reader",
+            read_frames(reader, session.inner.clone()),
+        )?;
+
         let auth = protocol::AuthParams {
             version: protocol::MAX_VERSION,
             supports_shm: false,
             supports_memfd: false,
-            cookie,
+            cookie: address.cookie.clone(),
         };
 
         // Authenticate with the socket
-        protocol::write_command_message(
-            sock.get_mut(),
-            0,
-            protocol::Command::Auth(auth),
-            protocol::MAX_VERSION,
-        )?;
-        let (seq, auth_info) =
-            protocol::read_reply_message::<protocol::AuthReply>(&mut sock, protocol::MAX_VERSION)?;
-        ensure!(seq == 0, "Sequence Mismatch");
+        let auth_info = session
+            .request::<protocol::AuthReply>(protocol::Command::Auth(auth))
+            .await?;
         let protocol_version = std::cmp::min(protocol::MAX_VERSION, auth_info.version);
+        session
+            .inner
+            .protocol_version
+            .store(protocol_version, Ordering::Relaxed);
 
         // The next step is to set the client name.
         let mut props = protocol::Props::new();
-        props.set(
-            protocol::Prop::ApplicationName,
-            CString::new(client_name).unwrap(),
-        );
-        protocol::write_command_message(
-            sock.get_mut(),
-            1,
-            protocol::Command::SetClientName(props),
-            protocol_version,
-        )?;
+        props.set(protocol::Prop::ApplicationName, CString::new(client_name)?);
+        session
+            .request::<protocol::SetClientNameReply>(protocol::Command::SetClientName(props))
+            .await?;
 
-        // Read the client name set reply
-        let (seq, _) = protocol::read_reply_message::<protocol::SetClientNameReply>(
-            &mut sock,
-            protocol_version,
-        )?;
-        ensure!(seq == 1, "Sequence Mismatch");
+        Ok(session)
+    }
 
-        Ok(Self {
-            sequence: 2,
-            socket: sock,
-            protocol_version,
+    /// Cancelled once the connection to the server is gone.
+    pub fn closed(&self) -> CancellationToken {
+        self.inner.closed.clone()
+    }
+
+    /// Disconnects from the server, failing anything still waiting on it.
+    pub async fn close(&self) {
+        self.inner.closed.cancel();
+        if let Err(err) = self.inner.writer.lock().await.shutdown().await {
+            tracing::debug!("Could not shut down PulseAudio socket: {:?}", err);
+        }
+    }
+
+    pub async fn get_sink_inputs(&self) -> Result<Vec<protocol::SinkInputInfo>> {
+        self.request::<protocol::SinkInputInfoList>(protocol::Command::GetSinkInputInfoList)
+            .await
+    }
+
+    pub async fn get_sinks(&self) -> Result<Vec<protocol::SinkInfo>> {
+        self.request::<protocol::SinkInfoList>(protocol::Command::GetSinkInfoList)
+            .await
+    }
+
+    pub async fn get_sources(&self) -> Result<Vec<protocol::SourceInfo>> {
+        self.request::<protocol::SourceInfoList>(protocol::Command::GetSourceInfoList)
+            .await
+    }
+
+    pub async fn get_clients(&self) -> Result<Vec<protocol::ClientInfo>> {
+        self.request::<protocol::ClientInfoList>(protocol::Command::GetClientInfoList)
+            .await
+    }
+
+    pub async fn get_sink(&self, index: u32) -> Result<protocol::SinkInfo> {
+        self.request::<protocol::SinkInfo>(protocol::Command::GetSinkInfo(protocol::GetSinkInfo {
+            index: Some(index),
+            name: None,
+        }))
+        .await
+    }
+
+    pub async fn get_source(&self, index: u32) -> Result<protocol::SourceInfo> {
+        self.request::<protocol::SourceInfo>(protocol::Command::GetSourceInfo(
+            protocol::GetSourceInfo {
+                index: Some(index),
+                name: None,
+            },
+        ))
+        .await
+    }
+
+    pub async fn get_sink_input(&self, index: u32) -> Result<protocol::SinkInputInfo> {
+        self.request::<protocol::SinkInputInfo>(protocol::Command::GetSinkInputInfo(index))
+            .await
+    }
+
+    pub async fn get_client(&self, index: u32) -> Result<protocol::ClientInfo> {
+        self.request::<protocol::ClientInfo>(protocol::Command::GetClientInfo(index))
+            .await
+    }
+
+    /// Server info, including the default sink and source.
+    pub async fn get_server_info(&self) -> Result<protocol::ServerInfo> {
+        self.request::<protocol::ServerInfo>(protocol::Command::GetServerInfo)
+            .await
+    }
+
+    pub async fn set_sink_volume(&self, index: u32, volume: protocol::ChannelVolume) -> Result<()> {
+        self.write(protocol::Command::SetSinkVolume(
+            protocol::SetDeviceVolumeParams {
+                device_index: Some(index),
+                device_name: None,
+                volume,
+            },
+        ))
+        .await
+    }
+
+    pub async fn set_source_volume(
+        &self,
+        index: u32,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.write(protocol::Command::SetSourceVolume(
+            protocol::SetDeviceVolumeParams {
+                device_index: Some(index),
+                device_name: None,
+                volume,
+            },
+        ))
+        .await
+    }
+
+    pub async fn set_sink_input_volume(
+        &self,
+        index: u32,
+        volume: protocol::ChannelVolume,
+    ) -> Result<()> {
+        self.write(protocol::Command::SetSinkInputVolume(
+            protocol::SetStreamVolumeParams { index, volume },
+        ))
+        .await
+    }
+
+    pub async fn set_sink_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.write(protocol::Command::SetSinkMute(
+            protocol::SetDeviceMuteParams {
+                device_index: Some(index),
+                device_name: None,
+                mute,
+            },
+        ))
+        .await
+    }
+
+    pub async fn set_source_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.write(protocol::Command::SetSourceMute(
+            protocol::SetDeviceMuteParams {
+                device_index: Some(index),
+                device_name: None,
+                mute,
+            },
+        ))
+        .await
+    }
+
+    pub async fn set_sink_input_mute(&self, index: u32, mute: bool) -> Result<()> {
+        self.write(protocol::Command::SetSinkInputMute(
+            protocol::SetStreamMuteParams { index, mute },
+        ))
+        .await
+    }
+
+    /// Moves the sink input `index` to play on the sink `sink`.
+    pub async fn move_sink_input(&self, index: u32, sink: u32) -> Result<()> {
+        self.write(protocol::Command::MoveSinkInput(
+            protocol::MoveStreamParams {
+                index,
+                device_index: Some(sink),
+                device_name: None,
+            },
+        ))
+        .await
+    }
+
+    /// Defaults are set by name, the server doesn't take indexes for these.
+    pub async fn set_default_sink(&self, name: &str) -> Result<()> {
+        self.write(protocol::Command::SetDefaultSink(Some(CString::new(name)?)))
+            .await
+    }
+
+    pub async fn set_default_source(&self, name: &str) -> Result<()> {
+        self.write(protocol::Command::SetDefaultSource(Some(CString::new(
+            name,
+        )?)))
+        .await
+    }
+
+    /// Starts recording the peak level of the source `index`.
+    ///
+    /// Record from a sink's monitor source for its output level. The server does the peak
+    /// detection, so this is a few bytes a second however loud it is.
+    pub async fn record_peaks(&self, index: u32) -> Result<PeakStream> {
+        let mut props = protocol::Props::new();
+        props.set(protocol::Prop::MediaName, CString::new("Peak meter")?);
+
+        let params = protocol::RecordStreamParams {
+            source_index: Some(index),
+            sample_spec: protocol::SampleSpec {
+                format: protocol::SampleFormat::Float32Le,
+                channels: 1,
+                sample_rate: PEAK_RATE,
+            },
+            channel_map: protocol::ChannelMap::mono(),
+            buffer_attr: protocol::RecordBufferAttr {
+                max_length: u32::MAX,
+                fragment_size: std::mem::size_of::<f32>() as u32,
+            },
+            flags: protocol::StreamFlags {
+                peak_detect: true,
+                adjust_latency: true,
+                // Meters should stay on the device they were opened for
+                no_move: true,
+                ..Default::default()
+            },
+            props,
+            ..Default::default()
+        };
+        let reply = self
+            .request::<protocol::CreateRecordStreamReply>(protocol::Command::CreateRecordStream(
+                params,
+            ))
+            .await?;
+
+        // Anything recorded before this is dropped, which is fine for a meter
+        let level = Arc::new(PeakLevel::default());
+        self.inner
+            .peaks
+            .lock()
+            .unwrap()
+            .insert(reply.channel, level.clone());
+
+        Ok(PeakStream {
+            session: self.clone(),
+            channel: reply.channel,
+            level,
         })
     }
 
-    pub fn get_sink_inputs(&mut self) -> Result<Vec<protocol::SinkInputInfo>> {
-        // Send Command
-        protocol::write_command_message(
-            self.socket.get_mut(),
-            self.sequence,
-            protocol::Command::GetSinkInputInfoList,
-            self.protocol_version,
-        )?;
+    /// Starts receiving the events in `mask`.
+    pub async fn subscribe(
+        &self,
+        mask: protocol::SubscriptionMask,
+    ) -> Result<broadcast::Receiver<protocol::SubscriptionEvent>> {
+        // Subscribe to the channel first so no events are missed
+        let events = self.inner.events.subscribe();
+        self.write(protocol::Command::Subscribe(mask)).await?;
+        Ok(events)
+    }
+
+    /// Sends a command that's answered with a plain ACK.
+    pub async fn write(&self, command: protocol::Command) -> Result<()> {
+        let (sequence, frame) = self.send(command).await?;
+
+        // Get ACK
+        let ack_seq = protocol::read_ack_message(&mut Cursor::new(frame))?;
+        ensure!(sequence == ack_seq, "Sequence Mismatch");
+
+        Ok(())
+    }
+
+    // Sends a command that's answered with a reply of type `R`
+    async fn request<R: protocol::CommandReply>(&self, command: protocol::Command) -> Result<R> {
+        let (sequence, frame) = self.send(command).await?;
 
         // Read Response
-        let (ack_seq, info_list) = protocol::read_reply_message::<protocol::SinkInputInfoList>(
-            &mut self.socket,
-            self.protocol_version,
-        )?;
-        ensure!(self.sequence == ack_seq, anyhow!("Sequence Mismatch"));
-        self.sequence += 1;
+        let (ack_seq, reply) =
+            protocol::read_reply_message::<R>(&mut Cursor::new(frame), self.protocol_version())?;
+        ensure!(sequence == ack_seq, "Sequence Mismatch");
 
-        Ok(info_list)
+        Ok(reply)
     }
 
-    pub fn write(&mut self, command: protocol::Command) -> Result<()> {
-        match command {
-            protocol::Command::Subscribe(_) => {
-                // Send Command
-                protocol::write_command_message(
-                    self.socket.get_mut(),
-                    self.sequence,
-                    command,
-                    self.protocol_version,
-                )?;
+    // Sends a command and waits for the frame answering it
+    async fn send(&self, command: protocol::Command) -> Result<(u32, Vec<u8>)> {
+        let sequence = self.inner.sequence.fetch_add(1, Ordering::Relaxed);
+        tracing::trace!("Sending {:?} as {}", command, sequence);
 
-                // Get ACK
-                let ack_seq = protocol::read_ack_message(&mut self.socket)?;
-                ensure!(self.sequence == ack_seq, anyhow!("Sequence Mismatch"));
-                self.sequence += 1;
+        let mut buffer = Vec::new();
+        protocol::write_command_message(&mut buffer, sequence, command, self.protocol_version())?;
 
-                Ok(())
-            }
-            unknown => {
-                debug!("Unknown command type: {:?}", unknown);
+        // Registered before sending, so the reply can't arrive with nobody waiting for it
+        let (tx, rx) = oneshot::channel();
+        self.inner.pending.lock().unwrap().insert(sequence, tx);
 
-                Ok(())
-            }
+        let written = self.inner.writer.lock().await.write_all(&buffer).await;
+        if let Err(err) = written {
+            self.inner.pending.lock().unwrap().remove(&sequence);
+            return Err(err.into());
         }
+
+        // Nothing reads the reply once the session is closed
+        let frame = tokio::select! {
+            frame = rx => frame.ok(),
+            _ = self.inner.closed.cancelled() => None,
+        };
+        let frame = frame.ok_or(anyhow!("PulseAudio connection closed"))?;
+        Ok((sequence, frame))
     }
 
-    pub fn subscribe(
-        &mut self,
-        mask: protocol::SubscriptionMask,
-        sender: &crossbeam_channel::Sender<protocol::SubscriptionEvent>,
-    ) -> Result<()> {
-        self.write(protocol::Command::Subscribe(mask))?;
+    fn protocol_version(&self) -> u16 {
+        self.inner.protocol_version.load(Ordering::Relaxed)
+    }
+}
+
+/// Peak levels recorded from a source, stopped when dropped.
+#[derive(Debug)]
+pub struct PeakStream {
+    session: PulseAudioSession,
+    channel: u32,
+    level: Arc<PeakLevel>,
+}
+
+impl PeakStream {
+    /// The highest level since the last call, 0.0 to 1.0.
+    pub fn take_peak(&self) -> f32 {
+        f32::from_bits(self.level.peak.swap(0, Ordering::Relaxed))
+    }
 
-        loop {
-            let (_, event) =
-                protocol::read_command_message(&mut self.socket, self.protocol_version)?;
+    /// Whether the server stopped the stream, like when its source went away.
+    pub fn is_killed(&self) -> bool {
+        self.level.killed.load(Ordering::Relaxed) || self.session.inner.closed.is_cancelled()
+    }
+}
+
+impl Drop for PeakStream {
+    fn drop(&mut self) {
+        self.session
+            .inner
+            .peaks
+            .lock()
+            .unwrap()
+            .remove(&self.channel);
+        if self.is_killed() {
+            return;
+        }
+
+        // Spawning needs a runtime, without one the stream goes when the connection does
+        let channel = self.channel;
+        if tokio::runtime::Handle::try_current().is_err() {
+            tracing::debug!("Not deleting record stream {} outside a runtime", channel);
+            return;
+        }
+
+        let session = self.session.clone();
+        let deleted = task::spawn("pulseaudio delete stream", async move {
+            session
+                .write(protocol::Command::DeleteRecordStream(channel))
+                .await
+        });
+        if let Err(err) = deleted {
+            tracing::warn!("Could not delete record stream {}: {:?}", channel, err);
+        }
+    }
+}
+
+async fn read_frames(mut reader: OwnedReadHalf, inner: Arc<Inner>) -> Result<()> {
+    let result = tokio::select! {
+        result = dispatch_frames(&mut reader, &inner) => result,
+        // Closed on purpose, nothing went wrong
+        _ = inner.closed.cancelled() => Ok(()),
+    };
+
+    // Dropping the waiting senders fails every request still in flight
+    inner.pending.lock().unwrap().clear();
+    inner.closed.cancel();
+
+    if let Err(ref err) = result {
+        tracing::warn!("PulseAudio connection lost: {:?}", err);
+    }
+    result
+}
+
+async fn dispatch_frames(reader: &mut OwnedReadHalf, inner: &Inner) -> Result<()> {
+    loop {
+        let mut frame = vec![0; DESCRIPTOR_LENGTH];
+        reader.read_exact(&mut frame).await?;
+
+        let length = u32::from_be_bytes(frame[0..4].try_into()?) as usize;
+        let channel = u32::from_be_bytes(frame[4..8].try_into()?);
+        ensure!(
+            length <= FRAME_SIZE_MAX_ALLOW,
+            "Protocol error: frame of {length} bytes is too large"
+        );
+        frame.resize(DESCRIPTOR_LENGTH + length, 0);
+        reader.read_exact(&mut frame[DESCRIPTOR_LENGTH..]).await?;
 
-            match event {
-                protocol::Command::SubscribeEvent(event) => {
-                    if let Err(err) = sender.send(event) {
-                        error!("Could not send event: {:?}", err);
-                    }
+        if channel != CONTROL_CHANNEL {
+            let level = inner.peaks.lock().unwrap().get(&channel).cloned();
+            match level {
+                Some(level) => record_peak(&level, &frame[DESCRIPTOR_LENGTH..]),
+                None => tracing::trace!("Ignoring {} bytes for channel {}", length, channel),
+            }
+            continue;
+        }
+
+        // Packets start with the command and sequence number as u32 tags
+        let payload = &frame[DESCRIPTOR_LENGTH..];
+        let (Some(command), Some(sequence)) = (read_u32_tag(payload, 0), read_u32_tag(payload, 5))
+        else {
+            tracing::error!("Got malformed packet {:02x?}", payload);
+            continue;
+        };
+
+        if command == COMMAND_REPLY || command == COMMAND_ERROR {
+            match inner.pending.lock().unwrap().remove(&sequence) {
+                Some(tx) => {
+                    // The request might have been given up on
+                    let _ = tx.send(frame);
                 }
-                _ => error!("Got unexpected event {:?}", event),
+                None => tracing::warn!("Got a reply to unknown request {}", sequence),
             }
+            continue;
         }
+
+        let version = inner.protocol_version.load(Ordering::Relaxed);
+        match protocol::read_command_message(&mut Cursor::new(frame), version) {
+            Ok((_, protocol::Command::SubscribeEvent(event))) => {
+                // Nobody subscribed is fine
+                let _ = inner.events.send(event);
+            }
+            Ok((_, protocol::Command::RecordStreamKilled(channel))) => {
+                if let Some(level) = inner.peaks.lock().unwrap().remove(&channel) {
+                    level.killed.store(true, Ordering::Relaxed);
+                }
+            }
+            Ok((_, command)) => tracing::debug!("Got unexpected command {:?}", command),
+            Err(err) => tracing::error!("Could not read command {}: {:?}", command, err),
+        }
+    }
+}
+
+// Keeps the highest of the samples in `data`, little endian floats
+fn record_peak(level: &PeakLevel, data: &[u8]) {
+    let peak = data
+        .chunks_exact(4)
+        .map(|sample| f32::from_le_bytes(sample.try_into().unwrap()).abs())
+        .fold(0.0, f32::max)
+        .min(1.0);
+
+    // Bits of non-negative floats sort the same as the floats do
+    level.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+}
+
+// A u32 tag is 'L' followed by the value, big endian
+fn read_u32_tag(payload: &[u8], offset: usize) -> Option<u32> {
+    let tag = payload.get(offset..offset + 5)?;
+    if tag[0] != b'L' {
+        return None;
     }
+    Some(u32::from_be_bytes(tag[1..5].try_into().ok()?))
 }
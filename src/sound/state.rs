@@ -0,0 +1,394 @@
+//! The audio objects apps see, and how changes to them are applied.
+//!
+//! Nothing in here talks to the server, so the bookkeeping can be tested on its own.
+
+/// An output device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sink {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// Average over the channels, 1.0 is 100%.
+    pub volume: f32,
+    pub channels: usize,
+    pub muted: bool,
+    /// Icon name and form factor (headset, speaker, ...) from the device properties.
+    pub icon_name: Option<String>,
+    pub form_factor: Option<String>,
+}
+
+/// An input device, including the monitors of sinks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Source {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    pub volume: f32,
+    pub channels: usize,
+    pub muted: bool,
+    pub icon_name: Option<String>,
+    pub form_factor: Option<String>,
+    /// The sink this monitors, if it's a monitor.
+    pub monitor_of: Option<u32>,
+}
+
+/// Audio an application is playing, a sink input in PulseAudio terms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stream {
+    pub index: u32,
+    pub name: String,
+    /// Client that's playing it.
+    pub client: Option<u32>,
+    /// Name and icon the application gave itself.
+    pub app_name: Option<String>,
+    pub icon_name: Option<String>,
+    /// Sink it's playing on.
+    pub sink: u32,
+    pub volume: f32,
+    pub channels: usize,
+    pub muted: bool,
+}
+
+/// A program connected to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub index: u32,
+    pub name: String,
+}
+
+/// What kind of object an index refers to, indexes are only unique per kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Sink,
+    Source,
+    Stream,
+    Client,
+}
+
+/// Any one of the objects in the state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioObject {
+    Sink(Sink),
+    Source(Source),
+    Stream(Stream),
+    Client(Client),
+}
+
+impl AudioObject {
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            AudioObject::Sink(_) => ObjectKind::Sink,
+            AudioObject::Source(_) => ObjectKind::Source,
+            AudioObject::Stream(_) => ObjectKind::Stream,
+            AudioObject::Client(_) => ObjectKind::Client,
+        }
+    }
+
+    pub fn index(&self) -> u32 {
+        match self {
+            AudioObject::Sink(sink) => sink.index,
+            AudioObject::Source(source) => source.index,
+            AudioObject::Stream(stream) => stream.index,
+            AudioObject::Client(client) => client.index,
+        }
+    }
+}
+
+/// A change to the state, sent to apps after it's been applied.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioEvent {
+    Added(AudioObject),
+    Changed(AudioObject),
+    Removed(ObjectKind, u32),
+    DefaultsChanged {
+        sink: Option<String>,
+        source: Option<String>,
+    },
+    /// The connection to the server was lost and made again, after the events bringing the
+    /// state up to date. Anything tied to the old connection, like peak streams, is gone.
+    AudioServiceRestarted,
+}
+
+/// Everything the audio apps draw from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AudioState {
+    pub sinks: Vec<Sink>,
+    pub sources: Vec<Source>,
+    pub streams: Vec<Stream>,
+    pub clients: Vec<Client>,
+    pub default_sink: Option<String>,
+    pub default_source: Option<String>,
+}
+
+impl AudioState {
+    pub fn sink(&self, index: u32) -> Option<&Sink> {
+        self.sinks.iter().find(|sink| sink.index == index)
+    }
+
+    pub fn source(&self, index: u32) -> Option<&Source> {
+        self.sources.iter().find(|source| source.index == index)
+    }
+
+    pub fn stream(&self, index: u32) -> Option<&Stream> {
+        self.streams.iter().find(|stream| stream.index == index)
+    }
+
+    pub fn client(&self, index: u32) -> Option<&Client> {
+        self.clients.iter().find(|client| client.index == index)
+    }
+
+    /// What to call a stream, the application's name if it gave one.
+    pub fn stream_label<'a>(&'a self, stream: &'a Stream) -> &'a str {
+        let client = stream.client.and_then(|index| self.client(index));
+        stream
+            .app_name
+            .as_deref()
+            .or(client.map(|client| client.name.as_str()))
+            .unwrap_or(&stream.name)
+    }
+
+    /// The default sink, if the server has one and it's known.
+    pub fn default_sink(&self) -> Option<&Sink> {
+        let name = self.default_sink.as_deref()?;
+        self.sinks.iter().find(|sink| sink.name == name)
+    }
+
+    pub fn default_source(&self) -> Option<&Source> {
+        let name = self.default_source.as_deref()?;
+        self.sources.iter().find(|source| source.name == name)
+    }
+
+    /// Adds or replaces an object, `None` if it was already up to date.
+    pub fn upsert(&mut self, object: AudioObject) -> Option<AudioEvent> {
+        let added = match object.clone() {
+            AudioObject::Sink(sink) => upsert(&mut self.sinks, sink, |s| s.index)?,
+            AudioObject::Source(source) => upsert(&mut self.sources, source, |s| s.index)?,
+            AudioObject::Stream(stream) => upsert(&mut self.streams, stream, |s| s.index)?,
+            AudioObject::Client(client) => upsert(&mut self.clients, client, |c| c.index)?,
+        };
+
+        Some(if added {
+            AudioEvent::Added(object)
+        } else {
+            AudioEvent::Changed(object)
+        })
+    }
+
+    /// Removes an object, `None` if it wasn't there.
+    pub fn remove(&mut self, kind: ObjectKind, index: u32) -> Option<AudioEvent> {
+        let removed = match kind {
+            ObjectKind::Sink => remove(&mut self.sinks, index, |s| s.index),
+            ObjectKind::Source => remove(&mut self.sources, index, |s| s.index),
+            ObjectKind::Stream => remove(&mut self.streams, index, |s| s.index),
+            ObjectKind::Client => remove(&mut self.clients, index, |c| c.index),
+        };
+        removed.then_some(AudioEvent::Removed(kind, index))
+    }
+
+    pub fn set_defaults(
+        &mut self,
+        sink: Option<String>,
+        source: Option<String>,
+    ) -> Option<AudioEvent> {
+        if self.default_sink == sink && self.default_source == source {
+            return None;
+        }
+
+        self.default_sink = sink.clone();
+        self.default_source = source.clone();
+        Some(AudioEvent::DefaultsChanged { sink, source })
+    }
+
+    /// Makes this state match `other`, returning the events that gets there.
+    pub fn replace(&mut self, other: AudioState) -> Vec<AudioEvent> {
+        let mut events = vec![];
+
+        // Removals first, so apps never see two objects with the same index
+        let removed: Vec<(ObjectKind, u32)> = self
+            .objects()
+            .filter(|object| !other.contains(object.kind(), object.index()))
+            .map(|object| (object.kind(), object.index()))
+            .collect();
+        for (kind, index) in removed {
+            events.extend(self.remove(kind, index));
+        }
+
+        for object in other.objects() {
+            events.extend(self.upsert(object));
+        }
+        events.extend(self.set_defaults(other.default_sink, other.default_source));
+
+        events
+    }
+
+    fn contains(&self, kind: ObjectKind, index: u32) -> bool {
+        match kind {
+            ObjectKind::Sink => self.sink(index).is_some(),
+            ObjectKind::Source => self.source(index).is_some(),
+            ObjectKind::Stream => self.stream(index).is_some(),
+            ObjectKind::Client => self.client(index).is_some(),
+        }
+    }
+
+    fn objects(&self) -> impl Iterator<Item = AudioObject> + '_ {
+        let sinks = self.sinks.iter().cloned().map(AudioObject::Sink);
+        let sources = self.sources.iter().cloned().map(AudioObject::Source);
+        let streams = self.streams.iter().cloned().map(AudioObject::Stream);
+        let clients = self.clients.iter().cloned().map(AudioObject::Client);
+        sinks.chain(sources).chain(streams).chain(clients)
+    }
+}
+
+// Some(true) if it was added, Some(false) if it replaced a different one
+fn upsert<T: PartialEq>(items: &mut Vec<T>, item: T, index: fn(&T) -> u32) -> Option<bool> {
+    match items
+        .iter_mut()
+        .find(|existing| index(existing) == index(&item))
+    {
+        Some(existing) if *existing == item => None,
+        Some(existing) => {
+            *existing = item;
+            Some(false)
+        }
+        None => {
+            items.push(item);
+            Some(true)
+        }
+    }
+}
+
+fn remove<T>(items: &mut Vec<T>, index: u32, item_index: fn(&T) -> u32) -> bool {
+    let before = items.len();
+    items.retain(|item| item_index(item) != index);
+    items.len() != before
+}
+
+/// Devices and streams for tests, change whatever the test is about.
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::*;
+
+    pub(crate) fn sink(index: u32, name: &str) -> Sink {
+        Sink {
+            index,
+            name: name.to_string(),
+            description: name.to_string(),
+            volume: 1.0,
+            channels: 2,
+            muted: false,
+            icon_name: None,
+            form_factor: None,
+        }
+    }
+
+    pub(crate) fn source(index: u32, name: &str, monitor_of: Option<u32>) -> Source {
+        Source {
+            index,
+            name: name.to_string(),
+            description: name.to_string(),
+            volume: 1.0,
+            channels: 2,
+            muted: false,
+            icon_name: None,
+            form_factor: None,
+            monitor_of,
+        }
+    }
+
+    pub(crate) fn stream(index: u32, sink: u32) -> Stream {
+        Stream {
+            index,
+            name: format!("Stream {}", index),
+            client: None,
+            app_name: None,
+            icon_name: None,
+            sink,
+            volume: 1.0,
+            channels: 2,
+            muted: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(index: u32, volume: f32) -> Stream {
+        Stream {
+            volume,
+            ..fixtures::stream(index, 0)
+        }
+    }
+
+    #[test]
+    fn upsert_and_remove() {
+        let mut state = AudioState::default();
+
+        let added = AudioObject::Stream(stream(4, 1.0));
+        assert_eq!(
+            state.upsert(added.clone()),
+            Some(AudioEvent::Added(added.clone()))
+        );
+        assert_eq!(state.upsert(added), None);
+
+        let changed = AudioObject::Stream(stream(4, 0.5));
+        assert_eq!(
+            state.upsert(changed.clone()),
+            Some(AudioEvent::Changed(changed))
+        );
+        assert_eq!(state.stream(4).map(|s| s.volume), Some(0.5));
+
+        assert_eq!(
+            state.remove(ObjectKind::Stream, 4),
+            Some(AudioEvent::Removed(ObjectKind::Stream, 4))
+        );
+        assert_eq!(state.remove(ObjectKind::Stream, 4), None);
+        assert!(state.streams.is_empty());
+    }
+
+    #[test]
+    fn indexes_are_per_kind() {
+        let mut state = AudioState::default();
+        state.upsert(AudioObject::Stream(stream(1, 1.0)));
+        state.upsert(AudioObject::Client(Client {
+            index: 1,
+            name: "Client".to_string(),
+        }));
+
+        assert_eq!(
+            state.remove(ObjectKind::Client, 1),
+            Some(AudioEvent::Removed(ObjectKind::Client, 1))
+        );
+        assert!(state.stream(1).is_some());
+    }
+
+    #[test]
+    fn replace_emits_the_difference() {
+        let mut state = AudioState {
+            streams: vec![stream(1, 1.0), stream(2, 1.0)],
+            ..Default::default()
+        };
+        let other = AudioState {
+            streams: vec![stream(2, 0.25), stream(3, 1.0)],
+            default_sink: Some("speakers".to_string()),
+            ..Default::default()
+        };
+
+        let events = state.replace(other.clone());
+        assert_eq!(
+            events,
+            vec![
+                AudioEvent::Removed(ObjectKind::Stream, 1),
+                AudioEvent::Changed(AudioObject::Stream(stream(2, 0.25))),
+                AudioEvent::Added(AudioObject::Stream(stream(3, 1.0))),
+                AudioEvent::DefaultsChanged {
+                    sink: Some("speakers".to_string()),
+                    source: None,
+                },
+            ]
+        );
+        assert_eq!(state, other);
+        assert!(state.replace(other).is_empty());
+    }
+}
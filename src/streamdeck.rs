@@ -1,23 +1,101 @@
-mod text;
-
-use std::sync::Arc;
+//! HID driver for the Stream Deck+.
+
+pub mod encoder;
+mod error;
+mod hid;
+#[cfg(feature = "text")]
+pub mod icon;
+mod input;
+#[cfg(feature = "text")]
+pub mod key_image;
+#[cfg(feature = "text")]
+pub mod text;
+mod view;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use anyhow::{anyhow, ensure, Result};
-use async_hid::{AccessMode, Device, DeviceInfo};
+use async_hid::{AccessMode, DeviceInfo};
 use futures_lite::StreamExt;
-use image::{codecs::jpeg::JpegEncoder, RgbImage};
+use image::{codecs::jpeg::JpegEncoder, imageops, RgbImage};
 use tokio::{
-    sync::{mpsc, RwLock},
+    sync::{mpsc, Mutex as AsyncMutex, RwLock},
     task::JoinHandle,
 };
 
-use self::text::font_renderer;
-
-pub type SubscriptionResult = (JoinHandle<Result<()>>, mpsc::Receiver<Input>);
-
+use self::hid::Hid;
+pub use self::{
+    error::DeckError,
+    input::{Input, InputError, INPUT_REPORT_LENGTH},
+    view::Visibility,
+};
+#[cfg(feature = "text")]
+use self::{
+    icon::{flatten, icon_cache, IconSource},
+    text::{font_renderer, TextStyle},
+};
+use crate::task;
+
+/// Handle to the input reader task and the channel it sends input to.
+pub type SubscriptionResult = (JoinHandle<Result<(), DeckError>>, mpsc::Receiver<Input>);
+
+/// Width of the LCD strip in pixels.
+pub const LCD_WIDTH: u32 = 800;
+/// Height of the LCD strip in pixels.
+pub const LCD_HEIGHT: u32 = 100;
+/// Number of keys.
+pub const KEY_COUNT: u8 = 8;
+/// Number of zones the LCD is split into, one above each encoder.
+pub const LCD_ZONES: u8 = 4;
+/// Width and height of a key image in pixels.
+pub const KEY_SIZE: u32 = 120;
+/// Width of one LCD zone in pixels.
+pub const ZONE_WIDTH: u32 = LCD_WIDTH / LCD_ZONES as u32;
+
+/// A connected Stream Deck+. Cheap to clone, all clones share the device.
 #[derive(Clone)]
 pub struct StreamDeckPlus {
-    device: Arc<RwLock<Device>>,
+    device: Arc<RwLock<Hid>>,
+    // Opened separately, so waiting for input never holds up writes to the device
+    input: Arc<AsyncMutex<Hid>>,
+    // Last brightness set, so waking up can restore it
+    brightness: Arc<AtomicU8>,
+    screen: Arc<Mutex<Screen>>,
+    suspended: Arc<AtomicBool>,
+    // Set on an app's handle, see [`view`](Self::view)
+    view: Option<Arc<view::View>>,
+}
+
+// What's been drawn, so it can be put back after something else takes over the deck
+#[derive(Debug)]
+struct Screen {
+    keys: [Option<RgbImage>; KEY_COUNT as usize],
+    lcd: RgbImage,
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self {
+            keys: Default::default(),
+            lcd: solid_image(LCD_WIDTH, LCD_HEIGHT, image::Rgb([0, 0, 0])),
+        }
+    }
+}
+
+/// Versions of the three firmware images on the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirmwareVersions {
+    /// The loader.
+    pub ld: String,
+    /// The secondary application.
+    pub ap1: String,
+    /// The primary application, what [`StreamDeckPlus::firmware_version`] returns.
+    pub ap2: String,
 }
 
 impl std::fmt::Debug for StreamDeckPlus {
@@ -26,93 +104,253 @@ impl std::fmt::Debug for StreamDeckPlus {
     }
 }
 
-#[allow(dead_code)]
 impl StreamDeckPlus {
-    pub async fn connect_exactly_one() -> Result<Self> {
-        let device = DeviceInfo::enumerate()
+    /// Opens the first Stream Deck+ found.
+    pub async fn connect_exactly_one() -> Result<Self, DeckError> {
+        let info = DeviceInfo::enumerate()
             .await?
             // StreamDeck Plus
             .find(|info: &DeviceInfo| info.matches(12, 1, 4057, 132))
             .await
-            .expect("Could not find device")
-            .open(AccessMode::ReadWrite)
-            .await?;
+            .ok_or(DeckError::NotFound)?;
+        let device = info.open(AccessMode::ReadWrite).await?;
+        let input = info.open(AccessMode::Read).await?;
+        Ok(Self::with_hid(Hid::Device(device), Hid::Device(input)))
+    }
 
-        Ok(Self {
+    fn with_hid(device: Hid, input: Hid) -> Self {
+        Self {
             device: Arc::new(RwLock::new(device)),
+            input: Arc::new(AsyncMutex::new(input)),
+            brightness: Arc::new(AtomicU8::new(100)),
+            screen: Arc::new(Mutex::new(Screen::default())),
+            suspended: Arc::new(AtomicBool::new(false)),
+            view: None,
+        }
+    }
+
+    // A deck that isn't connected to anything, for testing what gets written to it
+    #[cfg(test)]
+    pub(crate) fn fake() -> Self {
+        Self::with_hid(Hid::Fake(vec![]), Hid::Fake(vec![]))
+    }
+
+    // The reports written to a fake deck since the last call
+    #[cfg(test)]
+    pub(crate) async fn take_written(&self) -> Vec<Vec<u8>> {
+        match &mut *self.device.write().await {
+            Hid::Fake(written) => std::mem::take(written),
+            Hid::Device(_) => vec![],
+        }
+    }
+
+    /// Reads the serial number.
+    pub async fn serial_number(&self) -> Result<String, DeckError> {
+        let buffer = self.read_feature(0x06).await?;
+        Ok(extract_string(&buffer[1..]))
+    }
+
+    /// Reads the firmware version.
+    pub async fn firmware_version(&self) -> Result<String, DeckError> {
+        self.read_firmware(0x05).await
+    }
+
+    /// Reads the versions of all the firmware images.
+    pub async fn firmware_versions(&self) -> Result<FirmwareVersions, DeckError> {
+        Ok(FirmwareVersions {
+            ld: self.read_firmware(0x04).await?,
+            ap1: self.read_firmware(0x07).await?,
+            ap2: self.read_firmware(0x05).await?,
         })
     }
 
-    pub async fn serial_number(&self) -> Result<String> {
+    async fn read_firmware(&self, report_id: u8) -> Result<String, DeckError> {
+        let buffer = self.read_feature(report_id).await?;
+        // Not sure what the other five bytes of junk is
+        Ok(extract_string(&buffer[6..]))
+    }
+
+    async fn read_feature(&self, report_id: u8) -> Result<[u8; 32], DeckError> {
         let mut buffer = [0u8; 32];
-        buffer[0] = 0x06;
+        buffer[0] = report_id;
         let _size = self
             .device
             .read()
             .await
             .read_feature_report(&mut buffer)
             .await?;
-        extract_string(&buffer[1..])
+        Ok(buffer)
     }
 
-    pub async fn firmware_version(&self) -> Result<String> {
-        let mut buffer = [0u8; 32];
-        buffer[0] = 0x05;
-        let _size = self
-            .device
-            .read()
+    async fn write_feature(&self, data: &[u8]) -> Result<(), DeckError> {
+        // Feature reports are always padded out to 32 bytes
+        let mut buffer = data.to_vec();
+        buffer.resize(32, 0);
+
+        self.device
+            .write()
             .await
-            .read_feature_report(&mut buffer)
+            .write_feature_report(&mut buffer)
             .await?;
-        // Not sure what the other five bytes of junk is
-        extract_string(&buffer[6..])
+
+        Ok(())
     }
 
-    pub async fn read_input(&self) -> Result<Input> {
+    /// Waits for the next input report. Use [`subscribe`](Self::subscribe) to get a stream.
+    pub async fn read_input(&self) -> Result<Input, DeckError> {
         let mut buffer = [0u8; 14];
-        self.device
-            .read()
+        let size = self
+            .input
+            .lock()
             .await
             .read_input_report(&mut buffer)
             .await?;
-        buffer.try_into()
+
+        // A read that returns nothing means the device went away
+        if size == 0 {
+            return Err(DeckError::Disconnected);
+        }
+
+        Ok(buffer.try_into()?)
     }
 
-    pub fn subscribe(&self) -> Result<SubscriptionResult> {
+    /// Spawns a task that reads input and sends it to the returned channel.
+    pub fn subscribe(&self) -> Result<SubscriptionResult, DeckError> {
         let (tx, rx) = mpsc::channel::<Input>(10);
-        let handle = tokio::task::Builder::new()
-            .name("input reader")
-            .spawn(subscriber(tx, self.clone()))?;
+        let handle = task::spawn("input reader", subscriber(tx, self.clone()))?;
         Ok((handle, rx))
     }
 
-    pub async fn set_brightness(&self, percent: u8) -> Result<()> {
-        let mut buffer = vec![0x03, 0x08, percent];
-        buffer.extend(vec![0u8; 29]);
+    /// Sets the brightness of the keys and LCD, from 0 to 100.
+    pub async fn set_brightness(&self, percent: u8) -> Result<(), DeckError> {
+        check_bounds("Brightness", percent as u32, 100)?;
 
-        self.device
-            .write()
-            .await
-            .write_feature_report(&mut buffer)
-            .await?;
+        self.write_feature(&[0x03, 0x08, percent]).await?;
+        self.brightness.store(percent, Ordering::Relaxed);
 
         Ok(())
     }
 
-    pub async fn set_button_color(&self, index: u8, color: image::Rgb<u8>) -> Result<()> {
-        ensure!(index <= 7, anyhow!("Invalid button index"));
+    /// The last brightness that was set.
+    pub fn brightness(&self) -> u8 {
+        self.brightness.load(Ordering::Relaxed)
+    }
+
+    /// Resets the device, clearing the keys and LCD and showing the boot logo.
+    pub async fn reset(&self) -> Result<(), DeckError> {
+        self.write_feature(&[0x03, 0x02]).await
+    }
+
+    /// Puts the device to sleep after it hasn't been touched for `timeout`.
+    ///
+    /// The timeout is rounded down to whole seconds, zero turns sleeping off.
+    pub async fn set_sleep_timeout(&self, timeout: Duration) -> Result<(), DeckError> {
+        let seconds = u32::try_from(timeout.as_secs()).unwrap_or(u32::MAX);
+        let [a, b, c, d] = seconds.to_le_bytes();
+        self.write_feature(&[0x03, 0x0d, a, b, c, d]).await
+    }
 
-        let img = solid_image(120, 120, color);
+    /// Wakes the device from sleep, restoring the last brightness that was set.
+    pub async fn wake(&self) -> Result<(), DeckError> {
+        self.set_brightness(self.brightness()).await
+    }
+
+    /// Turns key `index` black.
+    pub async fn clear_key(&self, index: u8) -> Result<(), DeckError> {
+        self.set_button_color(index, image::Rgb([0, 0, 0])).await
+    }
+
+    /// Turns the whole LCD black.
+    pub async fn clear_lcd(&self) -> Result<(), DeckError> {
+        let img = solid_image(LCD_WIDTH, LCD_HEIGHT, image::Rgb([0, 0, 0]));
+        self.set_lcd_image(0, 0, &img).await
+    }
+
+    /// Turns every key and the LCD black.
+    pub async fn clear_all(&self) -> Result<(), DeckError> {
+        for index in 0..KEY_COUNT {
+            self.clear_key(index).await?;
+        }
+        self.clear_lcd().await
+    }
+
+    /// Fills key `index` with a single color.
+    pub async fn set_button_color(
+        &self,
+        index: u8,
+        color: image::Rgb<u8>,
+    ) -> Result<(), DeckError> {
+        let img = solid_image(KEY_SIZE, KEY_SIZE, color);
         self.set_button_image(index, &img).await
     }
 
-    pub async fn set_button_image(&self, index: u8, image: &RgbImage) -> Result<()> {
-        ensure!(index <= 7, anyhow!("Invalid button index"));
+    /// Stops drawing to the device, until [`resume_drawing`](Self::resume_drawing).
+    ///
+    /// Images set in the meantime are remembered and shown on resume, only the overlay
+    /// methods still draw.
+    pub fn suspend_drawing(&self) {
+        self.suspended.store(true, Ordering::Relaxed);
+    }
+
+    /// Redraws the keys and LCD with the last images set on them and starts drawing again.
+    pub async fn resume_drawing(&self) -> Result<(), DeckError> {
+        self.suspended.store(false, Ordering::Relaxed);
+
+        let (keys, lcd) = {
+            let screen = self.screen.lock().unwrap();
+            (screen.keys.clone(), screen.lcd.clone())
+        };
+
+        let black = solid_image(KEY_SIZE, KEY_SIZE, image::Rgb([0, 0, 0]));
+        for (index, image) in keys.iter().enumerate() {
+            let image = image.as_ref().unwrap_or(&black);
+            self.overlay_button_image(index as u8, image).await?;
+        }
+        self.overlay_lcd_image(0, 0, &lcd).await
+    }
+
+    /// Shows a 120x120 image on key `index`.
+    pub async fn set_button_image(&self, index: u8, image: &RgbImage) -> Result<(), DeckError> {
+        check_key_index(index)?;
+
+        let (width, height) = image.dimensions();
+        if (width, height) != (KEY_SIZE, KEY_SIZE) {
+            return Err(DeckError::InvalidImageSize(width, height));
+        }
+
+        if let Some(view) = &self.view {
+            if !view.draw_key(index, image) {
+                return Ok(());
+            }
+        }
+        self.show_button_image(index, image).await
+    }
+
+    // Draws on the device and remembers it, unless drawing is suspended
+    async fn show_button_image(&self, index: u8, image: &RgbImage) -> Result<(), DeckError> {
+        self.screen.lock().unwrap().keys[index as usize] = Some(image.clone());
+        if self.suspended.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.overlay_button_image(index, image).await
+    }
+
+    /// Shows a 120x120 image on key `index`, even while drawing is suspended.
+    ///
+    /// The image isn't remembered, so [`resume_drawing`](Self::resume_drawing) replaces it.
+    pub async fn overlay_button_image(&self, index: u8, image: &RgbImage) -> Result<(), DeckError> {
+        check_key_index(index)?;
+
+        let (width, height) = image.dimensions();
+        if (width, height) != (KEY_SIZE, KEY_SIZE) {
+            return Err(DeckError::InvalidImageSize(width, height));
+        }
 
         // Encode it as JPEG
         let mut image_data = Vec::new();
         let mut encoder = JpegEncoder::new(&mut image_data);
-        encoder.encode(image, 120, 120, image::ExtendedColorType::Rgb8)?;
+        encoder.encode(image, KEY_SIZE, KEY_SIZE, image::ExtendedColorType::Rgb8)?;
 
         // Write the image
         let image_report_length = 1024;
@@ -152,20 +390,94 @@ impl StreamDeckPlus {
         Ok(())
     }
 
-    pub async fn set_lcd_message(&self, text: String) -> Result<()> {
-        let mut renderer = font_renderer().lock().await;
-        let img = renderer.render_text(800, 100, text);
-        self.set_lcd_image(10, 10, &img).await?;
-        Ok(())
+    /// Shows an icon centered on key `index`.
+    #[cfg(feature = "text")]
+    pub async fn set_button_icon(
+        &self,
+        index: u8,
+        icon: &IconSource,
+        background: image::Rgb<u8>,
+    ) -> Result<(), DeckError> {
+        check_key_index(index)?;
+
+        // Loading the icon can fail for reasons that have nothing to do with the deck
+        let icon = icon_cache().load(icon, 96).await.map_err(DeckError::Icon)?;
+        let img = flatten(&icon, KEY_SIZE, KEY_SIZE, background);
+        self.set_button_image(index, &img).await
     }
 
-    // 800x100 is the dimensions
-    pub async fn set_lcd_image(&self, x: u16, y: u16, image: &RgbImage) -> Result<()> {
-        ensure!(x <= 800, anyhow!("x must be 800 or less"));
-        ensure!(y <= 800, anyhow!("7 must be 100 or less"));
+    /// Fills the LCD with a line of text.
+    #[cfg(feature = "text")]
+    pub async fn set_lcd_message(&self, text: String) -> Result<(), DeckError> {
+        let img = font_renderer()
+            .lock()
+            .await
+            .render_text(LCD_WIDTH, LCD_HEIGHT, text);
+        self.set_lcd_image(0, 0, &img).await
+    }
 
-        // Encode it as JPEG
+    /// Draws styled text into a box on the LCD.
+    #[cfg(feature = "text")]
+    pub async fn set_lcd_text(
+        &self,
+        x: u16,
+        y: u16,
+        width: u32,
+        height: u32,
+        text: &str,
+        style: &TextStyle,
+    ) -> Result<(), DeckError> {
+        let img = font_renderer()
+            .lock()
+            .await
+            .render_text_styled(width, height, text, style);
+        self.set_lcd_image(x, y, &img).await
+    }
+
+    /// Draws an image on the LCD with its top left corner at `x`, `y`.
+    ///
+    /// The image has to fit inside the 800x100 strip.
+    pub async fn set_lcd_image(&self, x: u16, y: u16, image: &RgbImage) -> Result<(), DeckError> {
+        check_lcd_bounds(x, y, image)?;
+
+        if let Some(view) = &self.view {
+            for (run_x, width) in view.draw_lcd(x, y, image) {
+                let run = imageops::crop_imm(image, run_x - x as u32, 0, width, image.height());
+                self.show_lcd_image(run_x as u16, y, &run.to_image())
+                    .await?;
+            }
+            return Ok(());
+        }
+        self.show_lcd_image(x, y, image).await
+    }
+
+    async fn show_lcd_image(&self, x: u16, y: u16, image: &RgbImage) -> Result<(), DeckError> {
+        imageops::replace(
+            &mut self.screen.lock().unwrap().lcd,
+            image,
+            x as i64,
+            y as i64,
+        );
+        if self.suspended.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        self.overlay_lcd_image(x, y, image).await
+    }
+
+    /// Draws an image on the LCD, even while drawing is suspended.
+    ///
+    /// The image isn't remembered, so [`resume_drawing`](Self::resume_drawing) replaces it.
+    pub async fn overlay_lcd_image(
+        &self,
+        x: u16,
+        y: u16,
+        image: &RgbImage,
+    ) -> Result<(), DeckError> {
+        check_lcd_bounds(x, y, image)?;
         let (width, height) = image.dimensions();
+
+        // Encode it as JPEG
         let mut image_data = Vec::new();
         let mut encoder = JpegEncoder::new(&mut image_data);
         encoder.encode(image, width, height, image::ExtendedColorType::Rgb8)?;
@@ -221,68 +533,39 @@ impl StreamDeckPlus {
     }
 }
 
-async fn subscriber(tx: mpsc::Sender<Input>, deck: StreamDeckPlus) -> Result<()> {
+async fn subscriber(tx: mpsc::Sender<Input>, deck: StreamDeckPlus) -> Result<(), DeckError> {
     loop {
         let input = deck.read_input().await?;
-        tx.send(input).await?;
-    }
-}
-
-#[derive(Debug)]
-pub enum Input {
-    None,
-    Buttons([bool; 8]),
-    EncoderPress([bool; 4]),
-    EncoderTwist([i8; 4]),
-}
-
-impl TryFrom<[u8; 14]> for Input {
-    type Error = anyhow::Error;
 
-    fn try_from(buffer: [u8; 14]) -> std::prelude::v1::Result<Self, Self::Error> {
-        if buffer[0] == 0x0 {
-            return Ok(Input::None);
+        // Nobody is listening anymore
+        if tx.send(input).await.is_err() {
+            return Ok(());
         }
+    }
+}
 
-        match buffer[1] {
-            0x0 => read_buttons(buffer),
-            // 0x2 => {}, // LCD Input
-            0x3 => read_encoders(buffer),
-            byte => Err(anyhow!("Unknown data type: {}", byte)),
-        }
+fn check_key_index(index: u8) -> Result<(), DeckError> {
+    if index >= KEY_COUNT {
+        return Err(DeckError::InvalidKeyIndex(index));
     }
+    Ok(())
 }
 
-fn read_buttons(buffer: [u8; 14]) -> Result<Input> {
-    // Data starts at 4 and continues for the number of buttons
-    let values: Vec<bool> = buffer[4..12].iter().map(|b| *b != 0).collect();
-    Ok(Input::Buttons([
-        values[0], values[1], values[2], values[3], values[4], values[5], values[6], values[7],
-    ]))
+fn check_lcd_bounds(x: u16, y: u16, image: &RgbImage) -> Result<(), DeckError> {
+    // The whole image has to fit on the strip
+    let (width, height) = image.dimensions();
+    check_bounds("LCD image right edge", x as u32 + width, LCD_WIDTH)?;
+    check_bounds("LCD image bottom edge", y as u32 + height, LCD_HEIGHT)
 }
 
-fn read_encoders(buffer: [u8; 14]) -> Result<Input> {
-    match buffer[4] {
-        // Encoder Press
-        // Data starts at 4 and continues for the number of encoders
-        0x0 => {
-            let values: Vec<bool> = buffer[5..9].iter().map(|b| *b != 0).collect();
-            Ok(Input::EncoderPress([
-                values[0], values[1], values[2], values[3],
-            ]))
-        }
-        // Encoder Twist
-        // Data starts at 4 and continues for the number of encoders
-        0x1 => {
-            let values: Vec<i8> = buffer[5..9].iter().map(|b| i8::from_le(*b as i8)).collect();
-            Ok(Input::EncoderTwist([
-                values[0], values[1], values[2], values[3],
-            ]))
-        }
-        _ => Err(anyhow!("Bad Encoder Data")),
+fn check_bounds(what: &'static str, value: u32, max: u32) -> Result<(), DeckError> {
+    if value > max {
+        return Err(DeckError::OutOfBounds { what, value, max });
     }
+    Ok(())
 }
 
+/// An image filled with a single color.
 pub fn solid_image(width: u32, height: u32, color: image::Rgb<u8>) -> RgbImage {
     // Create image of specified color
     let mut img = image::ImageBuffer::new(width, height);
@@ -292,7 +575,7 @@ pub fn solid_image(width: u32, height: u32, color: image::Rgb<u8>) -> RgbImage {
     img
 }
 
-fn extract_string(bytes: &[u8]) -> Result<String> {
+fn extract_string(bytes: &[u8]) -> String {
     // Find the position of the last non-NUL byte
     let last_non_nul_pos = bytes.iter().rposition(|&x| x != 0x00);
 
@@ -302,7 +585,6 @@ fn extract_string(bytes: &[u8]) -> Result<String> {
         None => bytes,
     };
 
-    // Convert the trimmed slice to a Vec<u8> because String::from_utf8 expects Vec<u8>
-    let trimmed_vec = trimmed_slice.to_vec();
-    Ok(String::from_utf8(trimmed_vec)?)
+    // The device only sends ASCII, so anything else is garbage anyway
+    String::from_utf8_lossy(trimmed_slice).into_owned()
 }
@@ -0,0 +1,339 @@
+//! Turning the raw encoder deltas into values worth using.
+//!
+//! Times are passed in (the `_at` methods) so the curves can be tested without sleeping.
+
+use std::time::{Duration, Instant};
+
+use super::Input;
+
+/// Speeds up twisting when the encoder is turned quickly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Acceleration {
+    /// Clicks closer together than this count as one fast twist.
+    pub window: Duration,
+    /// Added to the multiplier for each click of a fast twist.
+    pub factor: f64,
+    /// The multiplier never goes above this.
+    pub max_multiplier: f64,
+}
+
+impl Default for Acceleration {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_millis(80),
+            factor: 0.5,
+            max_multiplier: 8.0,
+        }
+    }
+}
+
+/// How one encoder's clicks turn into changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EncoderConfig {
+    /// How much one click changes the value.
+    pub step: f64,
+    pub acceleration: Option<Acceleration>,
+    /// A click in the other direction this soon after a twist is treated as the knob
+    /// bouncing on a detent and ignored.
+    pub debounce: Duration,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            step: 1.0,
+            acceleration: None,
+            debounce: Duration::from_millis(30),
+        }
+    }
+}
+
+impl EncoderConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn step(mut self, step: f64) -> Self {
+        self.step = step;
+        self
+    }
+
+    pub fn accelerated(mut self) -> Self {
+        self.acceleration = Some(Acceleration::default());
+        self
+    }
+
+    pub fn acceleration(mut self, acceleration: Option<Acceleration>) -> Self {
+        self.acceleration = acceleration;
+        self
+    }
+
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+}
+
+/// Tracks one encoder's recent twists and scales its deltas.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    config: EncoderConfig,
+    last_twist: Option<(Instant, i8)>,
+    // Clicks in the current fast twist
+    streak: u32,
+}
+
+impl Encoder {
+    pub fn new(config: EncoderConfig) -> Self {
+        Self {
+            config,
+            last_twist: None,
+            streak: 0,
+        }
+    }
+
+    pub fn config(&self) -> &EncoderConfig {
+        &self.config
+    }
+
+    /// Scales a raw delta from [`Input::EncoderTwist`].
+    pub fn twist(&mut self, delta: i8) -> f64 {
+        self.twist_at(delta, Instant::now())
+    }
+
+    pub fn twist_at(&mut self, delta: i8, now: Instant) -> f64 {
+        if delta == 0 {
+            return 0.0;
+        }
+
+        let direction = delta.signum();
+        let since_last = self
+            .last_twist
+            .map(|(at, last_direction)| (now.saturating_duration_since(at), last_direction));
+
+        match since_last {
+            // Bounced back on a detent, keep the streak going in the original direction
+            Some((elapsed, last_direction))
+                if last_direction != direction && elapsed < self.config.debounce =>
+            {
+                return 0.0;
+            }
+            Some((elapsed, last_direction)) if last_direction == direction => {
+                let fast = self
+                    .config
+                    .acceleration
+                    .is_some_and(|acceleration| elapsed < acceleration.window);
+                if !fast {
+                    self.streak = 0;
+                }
+            }
+            _ => self.streak = 0,
+        }
+
+        self.last_twist = Some((now, direction));
+        let multiplier = self.multiplier();
+        self.streak = self.streak.saturating_add(delta.unsigned_abs() as u32);
+
+        delta as f64 * self.config.step * multiplier
+    }
+
+    fn multiplier(&self) -> f64 {
+        match self.config.acceleration {
+            Some(acceleration) => (1.0 + self.streak as f64 * acceleration.factor)
+                .min(acceleration.max_multiplier)
+                .max(1.0),
+            None => 1.0,
+        }
+    }
+}
+
+/// All four encoders, each with its own config.
+#[derive(Debug, Clone)]
+pub struct Encoders {
+    encoders: [Encoder; 4],
+}
+
+impl Encoders {
+    pub fn new(configs: [EncoderConfig; 4]) -> Self {
+        Self {
+            encoders: configs.map(Encoder::new),
+        }
+    }
+
+    /// Every encoder with the same config.
+    pub fn uniform(config: EncoderConfig) -> Self {
+        Self::new([config; 4])
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut Encoder> {
+        self.encoders.get_mut(index)
+    }
+
+    /// Scaled deltas for a twist, `None` for any other input.
+    pub fn handle(&mut self, input: &Input) -> Option<[f64; 4]> {
+        self.handle_at(input, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, input: &Input, now: Instant) -> Option<[f64; 4]> {
+        let Input::EncoderTwist(deltas) = input else {
+            return None;
+        };
+
+        let mut scaled = [0.0; 4];
+        for ((value, encoder), delta) in scaled.iter_mut().zip(&mut self.encoders).zip(deltas) {
+            *value = encoder.twist_at(*delta, now);
+        }
+        Some(scaled)
+    }
+}
+
+/// A value bound to one encoder, kept within `min..=max`.
+#[derive(Debug, Clone)]
+pub struct EncoderValue {
+    index: usize,
+    value: f64,
+    min: f64,
+    max: f64,
+    encoder: Encoder,
+}
+
+impl EncoderValue {
+    /// Bound to encoder `index` (0-3), starting at `value`.
+    pub fn new(index: usize, value: f64, min: f64, max: f64, config: EncoderConfig) -> Self {
+        Self {
+            index,
+            value: value.clamp(min, max),
+            min,
+            max,
+            encoder: Encoder::new(config),
+        }
+    }
+
+    /// A 0-100 percentage, five points a click and accelerated.
+    pub fn percent(index: usize, value: f64) -> Self {
+        Self::new(
+            index,
+            value,
+            0.0,
+            100.0,
+            EncoderConfig::new().step(5.0).accelerated(),
+        )
+    }
+
+    pub fn value(&self) -> f64 {
+        self.value
+    }
+
+    /// Sets the value without emitting a change, e.g. when it changed elsewhere.
+    pub fn set(&mut self, value: f64) {
+        self.value = value.clamp(self.min, self.max);
+    }
+
+    /// The new value if `input` twisted this encoder and changed it.
+    pub fn handle(&mut self, input: &Input) -> Option<f64> {
+        self.handle_at(input, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, input: &Input, now: Instant) -> Option<f64> {
+        match input {
+            Input::EncoderTwist(deltas) => self.twist_at(*deltas.get(self.index)?, now),
+            _ => None,
+        }
+    }
+
+    /// Applies a raw delta, returning the new value if it changed.
+    pub fn twist(&mut self, delta: i8) -> Option<f64> {
+        self.twist_at(delta, Instant::now())
+    }
+
+    pub fn twist_at(&mut self, delta: i8, now: Instant) -> Option<f64> {
+        let change = self.encoder.twist_at(delta, now);
+        let value = (self.value + change).clamp(self.min, self.max);
+
+        if value == self.value {
+            return None;
+        }
+        self.value = value;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::ms;
+
+    #[test]
+    fn steps_without_acceleration() {
+        let mut encoder = Encoder::new(EncoderConfig::new().step(2.0));
+        let start = Instant::now();
+
+        assert_eq!(encoder.twist_at(1, start), 2.0);
+        assert_eq!(encoder.twist_at(3, start + ms(10)), 6.0);
+        assert_eq!(encoder.twist_at(-1, start + ms(500)), -2.0);
+        assert_eq!(encoder.twist_at(0, start + ms(510)), 0.0);
+    }
+
+    #[test]
+    fn fast_twists_accelerate() {
+        let mut encoder = Encoder::new(EncoderConfig::new().accelerated());
+        let start = Instant::now();
+
+        let steps: Vec<f64> = (0..4)
+            .map(|i| encoder.twist_at(1, start + ms(i * 40)))
+            .collect();
+        assert_eq!(steps, [1.0, 1.5, 2.0, 2.5]);
+
+        // Slowing down starts over
+        assert_eq!(encoder.twist_at(1, start + ms(1000)), 1.0);
+    }
+
+    #[test]
+    fn acceleration_is_capped() {
+        let mut encoder = Encoder::new(EncoderConfig::new().accelerated());
+        let start = Instant::now();
+
+        for i in 0..100 {
+            encoder.twist_at(1, start + ms(i * 10));
+        }
+        assert_eq!(encoder.twist_at(1, start + ms(1000)), 8.0);
+    }
+
+    #[test]
+    fn detent_bounce_is_ignored() {
+        let mut encoder = Encoder::new(EncoderConfig::new());
+        let start = Instant::now();
+
+        assert_eq!(encoder.twist_at(1, start), 1.0);
+        assert_eq!(encoder.twist_at(-1, start + ms(10)), 0.0);
+        assert_eq!(encoder.twist_at(-1, start + ms(200)), -1.0);
+    }
+
+    #[test]
+    fn value_is_clamped_and_emits_changes() {
+        let mut value = EncoderValue::new(2, 95.0, 0.0, 100.0, EncoderConfig::new().step(5.0));
+        let start = Instant::now();
+
+        assert_eq!(
+            value.handle_at(&Input::EncoderTwist([0, 0, 1, 0]), start),
+            Some(100.0)
+        );
+        assert_eq!(
+            value.handle_at(&Input::EncoderTwist([0, 0, 1, 0]), start + ms(500)),
+            None
+        );
+        assert_eq!(
+            value.handle_at(&Input::EncoderTwist([5, 0, 0, 0]), start + ms(1000)),
+            None
+        );
+        assert_eq!(
+            value.handle_at(&Input::Buttons([true; 8]), start + ms(1500)),
+            None
+        );
+        assert_eq!(
+            value.handle_at(&Input::EncoderTwist([0, 0, -2, 0]), start + ms(2000)),
+            Some(90.0)
+        );
+        assert_eq!(value.value(), 90.0);
+    }
+}
@@ -0,0 +1,71 @@
+use async_hid::HidError;
+
+use super::input::InputError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DeckError {
+    #[error("Could not find a Stream Deck+")]
+    NotFound,
+    #[error("Stream Deck+ disconnected")]
+    Disconnected,
+    #[error("Invalid key index {0}, must be 7 or less")]
+    InvalidKeyIndex(u8),
+    #[error("{what} is {value}, must be {max} or less")]
+    OutOfBounds {
+        what: &'static str,
+        value: u32,
+        max: u32,
+    },
+    #[error("Key images must be 120x120, got {0}x{1}")]
+    InvalidImageSize(u32, u32),
+    #[error("Could not encode image: {0}")]
+    ImageEncode(#[from] image::ImageError),
+    #[error("Could not load icon: {0}")]
+    Icon(anyhow::Error),
+    #[error("Could not decode input: {0}")]
+    Protocol(#[from] InputError),
+    #[error("HID error: {0}")]
+    Hid(HidError),
+    #[error("Could not spawn task: {0}")]
+    Spawn(#[from] std::io::Error),
+}
+
+impl DeckError {
+    // Worth reconnecting and trying again, rather than a bug in the caller
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, DeckError::NotFound | DeckError::Disconnected)
+    }
+}
+
+impl From<HidError> for DeckError {
+    fn from(err: HidError) -> Self {
+        if caused_by_disconnect(&err) {
+            DeckError::Disconnected
+        } else {
+            DeckError::Hid(err)
+        }
+    }
+}
+
+fn caused_by_disconnect(err: &(dyn std::error::Error + 'static)) -> bool {
+    use std::io::ErrorKind;
+
+    // ENODEV, what hidraw returns once the device has been unplugged
+    const NO_SUCH_DEVICE: i32 = 19;
+
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            return err.raw_os_error() == Some(NO_SUCH_DEVICE)
+                || matches!(
+                    err.kind(),
+                    ErrorKind::NotFound
+                        | ErrorKind::BrokenPipe
+                        | ErrorKind::NotConnected
+                        | ErrorKind::UnexpectedEof
+                );
+        }
+        source = err.source();
+    }
+    false
+}
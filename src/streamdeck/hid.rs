@@ -0,0 +1,50 @@
+//! The HID device behind a deck, or in tests a fake one that keeps what's written to it.
+
+use async_hid::{Device, HidError};
+
+pub(super) enum Hid {
+    Device(Device),
+    #[cfg(test)]
+    Fake(Vec<Vec<u8>>),
+}
+
+impl Hid {
+    pub(super) async fn read_input_report(&mut self, buffer: &mut [u8]) -> Result<usize, HidError> {
+        match self {
+            Hid::Device(device) => device.read_input_report(buffer).await,
+            // Reads nothing, the same as a device that's gone away
+            #[cfg(test)]
+            Hid::Fake(_) => Ok(0),
+        }
+    }
+
+    pub(super) async fn read_feature_report(&self, buffer: &mut [u8]) -> Result<usize, HidError> {
+        match self {
+            Hid::Device(device) => device.read_feature_report(buffer).await,
+            #[cfg(test)]
+            Hid::Fake(_) => Ok(buffer.len()),
+        }
+    }
+
+    pub(super) async fn write_feature_report(&mut self, buffer: &mut [u8]) -> Result<(), HidError> {
+        match self {
+            Hid::Device(device) => {
+                device.write_feature_report(buffer).await?;
+            }
+            #[cfg(test)]
+            Hid::Fake(written) => written.push(buffer.to_vec()),
+        }
+        Ok(())
+    }
+
+    pub(super) async fn write_output_report(&mut self, buffer: &[u8]) -> Result<(), HidError> {
+        match self {
+            Hid::Device(device) => {
+                device.write_output_report(buffer).await?;
+            }
+            #[cfg(test)]
+            Hid::Fake(written) => written.push(buffer.to_vec()),
+        }
+        Ok(())
+    }
+}
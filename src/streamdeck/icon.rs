@@ -0,0 +1,207 @@
+//! Key icons loaded from image files, SVGs and icon fonts, cached per size.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use anyhow::{anyhow, Result};
+use image::{imageops, RgbImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+
+use super::text::font_renderer;
+
+pub fn icon_cache() -> &'static IconCache {
+    static CACHE: OnceLock<IconCache> = OnceLock::new();
+    CACHE.get_or_init(IconCache::new)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum IconSource {
+    // PNG, JPEG, WebP or SVG, picked by file extension
+    File(PathBuf),
+    // A named glyph from a registered icon font
+    Glyph {
+        font: String,
+        name: String,
+        color: image::Rgb<u8>,
+    },
+}
+
+impl IconSource {
+    pub fn file(path: impl Into<PathBuf>) -> Self {
+        Self::File(path.into())
+    }
+
+    pub fn glyph(font: impl Into<String>, name: impl Into<String>) -> Self {
+        Self::Glyph {
+            font: font.into(),
+            name: name.into(),
+            color: image::Rgb([0xFF, 0xFF, 0xFF]),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IconFont {
+    family: String,
+    codepoints: HashMap<String, char>,
+}
+
+impl IconFont {
+    // Fonts like Material Symbols turn the icon name into the glyph with ligatures
+    pub fn ligatures(family: impl Into<String>) -> Self {
+        Self {
+            family: family.into(),
+            codepoints: HashMap::new(),
+        }
+    }
+
+    // Fonts like Nerd Fonts need a name to codepoint table
+    pub fn with_codepoints(
+        family: impl Into<String>,
+        codepoints: impl IntoIterator<Item = (String, char)>,
+    ) -> Self {
+        Self {
+            family: family.into(),
+            codepoints: codepoints.into_iter().collect(),
+        }
+    }
+
+    fn text_for(&self, name: &str) -> String {
+        match self.codepoints.get(name) {
+            Some(c) => c.to_string(),
+            None => name.to_string(),
+        }
+    }
+}
+
+// The maps are only locked to look up and insert, never while loading, so icons load in
+// parallel and the font renderer's lock is never taken while holding them
+#[derive(Debug, Default)]
+pub struct IconCache {
+    fonts: Mutex<HashMap<String, IconFont>>,
+    icons: Mutex<HashMap<(IconSource, u32), Arc<RgbaImage>>>,
+}
+
+impl IconCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register_font(&self, name: impl Into<String>, font: IconFont) {
+        self.fonts.lock().unwrap().insert(name.into(), font);
+    }
+
+    pub async fn load(&self, source: &IconSource, size: u32) -> Result<Arc<RgbaImage>> {
+        let key = (source.clone(), size);
+        if let Some(icon) = self.icons.lock().unwrap().get(&key) {
+            return Ok(icon.clone());
+        }
+
+        let icon = Arc::new(match source {
+            IconSource::File(path) => load_file(path, size).await?,
+            IconSource::Glyph { font, name, color } => {
+                let (family, text) = {
+                    let fonts = self.fonts.lock().unwrap();
+                    let font = fonts
+                        .get(font)
+                        .ok_or(anyhow!("Unknown icon font: {}", font))?;
+                    (font.family.clone(), font.text_for(name))
+                };
+                let mut renderer = font_renderer().lock().await;
+                renderer.render_glyph(&family, &text, size, *color)
+            }
+        });
+
+        // Two loads of the same icon can race, either result is fine to keep
+        self.icons.lock().unwrap().insert(key, icon.clone());
+        Ok(icon)
+    }
+
+    pub fn clear(&self) {
+        self.icons.lock().unwrap().clear();
+    }
+}
+
+async fn load_file(path: &Path, size: u32) -> Result<RgbaImage> {
+    let data = tokio::fs::read(path).await?;
+
+    let is_svg = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.eq_ignore_ascii_case("svg") || ext.eq_ignore_ascii_case("svgz"))
+        .unwrap_or(false);
+
+    // Decoding and rasterizing can take a while for big files
+    tokio::task::spawn_blocking(move || {
+        if is_svg {
+            rasterize_svg(&data, size)
+        } else {
+            load_raster(&data, size)
+        }
+    })
+    .await?
+}
+
+fn load_raster(data: &[u8], size: u32) -> Result<RgbaImage> {
+    let img = image::load_from_memory(data)?.to_rgba8();
+
+    // Scale to fit inside the square, keeping the aspect ratio
+    let (width, height) = img.dimensions();
+    let scale = size as f32 / width.max(height) as f32;
+    let new_width = ((width as f32 * scale).round() as u32).max(1);
+    let new_height = ((height as f32 * scale).round() as u32).max(1);
+    let scaled = imageops::resize(&img, new_width, new_height, imageops::FilterType::Lanczos3);
+
+    // Center it on a transparent square
+    let mut icon = RgbaImage::new(size, size);
+    imageops::overlay(
+        &mut icon,
+        &scaled,
+        ((size - new_width) / 2) as i64,
+        ((size - new_height) / 2) as i64,
+    );
+    Ok(icon)
+}
+
+fn rasterize_svg(data: &[u8], size: u32) -> Result<RgbaImage> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+
+    // Scale to fit inside the square, keeping the aspect ratio
+    let svg_size = tree.size();
+    let scale = size as f32 / svg_size.width().max(svg_size.height());
+    let offset_x = (size as f32 - svg_size.width() * scale) / 2.0;
+    let offset_y = (size as f32 - svg_size.height() * scale) / 2.0;
+    let transform =
+        tiny_skia::Transform::from_scale(scale, scale).post_translate(offset_x, offset_y);
+
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size, size).ok_or(anyhow!("Invalid icon size: {}", size))?;
+    resvg::render(&tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia stores premultiplied colors
+    let mut icon = RgbaImage::new(size, size);
+    for (pixel, color) in icon.pixels_mut().zip(pixmap.pixels()) {
+        let color = color.demultiply();
+        *pixel = image::Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    Ok(icon)
+}
+
+pub fn flatten(icon: &RgbaImage, width: u32, height: u32, background: image::Rgb<u8>) -> RgbImage {
+    let [r, g, b] = background.0;
+    let mut img = RgbaImage::from_pixel(width, height, image::Rgba([r, g, b, 0xFF]));
+
+    // Center the icon on the background
+    let (icon_width, icon_height) = icon.dimensions();
+    imageops::overlay(
+        &mut img,
+        icon,
+        (width as i64 - icon_width as i64) / 2,
+        (height as i64 - icon_height as i64) / 2,
+    );
+
+    image::DynamicImage::ImageRgba8(img).to_rgb8()
+}
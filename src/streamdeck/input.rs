@@ -0,0 +1,189 @@
+//! Decoding for the input reports the Stream Deck+ sends.
+//!
+//! This file only depends on std and thiserror so the fuzz target can include it directly.
+
+pub const INPUT_REPORT_LENGTH: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    None,
+    Buttons([bool; 8]),
+    EncoderPress([bool; 4]),
+    EncoderTwist([i8; 4]),
+    LcdTouch { x: u16, y: u16 },
+    LcdLongPress { x: u16, y: u16 },
+    LcdSwipe { from: (u16, u16), to: (u16, u16) },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InputError {
+    #[error("Input report too short: {0} bytes")]
+    TooShort(usize),
+    #[error("Unknown input type: {0:#04x}")]
+    UnknownType(u8),
+    #[error("Unknown encoder event: {0:#04x}")]
+    UnknownEncoderEvent(u8),
+    #[error("Unknown touch event: {0:#04x}")]
+    UnknownTouchEvent(u8),
+}
+
+impl Input {
+    pub fn decode(buffer: &[u8]) -> Result<Self, InputError> {
+        let buffer: &[u8; INPUT_REPORT_LENGTH] = buffer
+            .get(..INPUT_REPORT_LENGTH)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(InputError::TooShort(buffer.len()))?;
+
+        if buffer[0] == 0x0 {
+            return Ok(Input::None);
+        }
+
+        match buffer[1] {
+            0x0 => Ok(read_buttons(buffer)),
+            0x2 => read_touch(buffer),
+            0x3 => read_encoders(buffer),
+            byte => Err(InputError::UnknownType(byte)),
+        }
+    }
+}
+
+impl TryFrom<[u8; INPUT_REPORT_LENGTH]> for Input {
+    type Error = InputError;
+
+    fn try_from(buffer: [u8; INPUT_REPORT_LENGTH]) -> Result<Self, Self::Error> {
+        Input::decode(&buffer)
+    }
+}
+
+fn read_buttons(buffer: &[u8; INPUT_REPORT_LENGTH]) -> Input {
+    // Data starts at 4 and continues for the number of buttons
+    let mut values = [false; 8];
+    for (value, byte) in values.iter_mut().zip(&buffer[4..12]) {
+        *value = *byte != 0;
+    }
+    Input::Buttons(values)
+}
+
+fn read_encoders(buffer: &[u8; INPUT_REPORT_LENGTH]) -> Result<Input, InputError> {
+    // Data starts at 5 and continues for the number of encoders
+    let data = &buffer[5..9];
+    match buffer[4] {
+        // Encoder Press
+        0x0 => {
+            let mut values = [false; 4];
+            for (value, byte) in values.iter_mut().zip(data) {
+                *value = *byte != 0;
+            }
+            Ok(Input::EncoderPress(values))
+        }
+        // Encoder Twist
+        0x1 => {
+            let mut values = [0i8; 4];
+            for (value, byte) in values.iter_mut().zip(data) {
+                *value = *byte as i8;
+            }
+            Ok(Input::EncoderTwist(values))
+        }
+        byte => Err(InputError::UnknownEncoderEvent(byte)),
+    }
+}
+
+fn read_touch(buffer: &[u8; INPUT_REPORT_LENGTH]) -> Result<Input, InputError> {
+    // Coordinates are little endian, the swipe end point follows the start point
+    let read_u16 = |offset: usize| u16::from_le_bytes([buffer[offset], buffer[offset + 1]]);
+    let from = (read_u16(6), read_u16(8));
+
+    match buffer[4] {
+        0x1 => Ok(Input::LcdTouch {
+            x: from.0,
+            y: from.1,
+        }),
+        0x2 => Ok(Input::LcdLongPress {
+            x: from.0,
+            y: from.1,
+        }),
+        0x3 => Ok(Input::LcdSwipe {
+            from,
+            to: (read_u16(10), read_u16(12)),
+        }),
+        byte => Err(InputError::UnknownTouchEvent(byte)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[rustfmt::skip]
+    const REPORTS: &[([u8; INPUT_REPORT_LENGTH], Result<Input, InputError>)] = &[
+        // Empty report
+        ([0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::None)),
+        // Buttons
+        ([0x01, 0x00, 0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::Buttons([true, false, false, false, false, false, false, false]))),
+        ([0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00],
+            Ok(Input::Buttons([false, false, false, false, false, false, false, true]))),
+        ([0x01, 0x00, 0x08, 0x00, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00],
+            Ok(Input::Buttons([true, true, false, false, true, false, false, true]))),
+        ([0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::Buttons([false; 8]))),
+        // Encoder presses
+        ([0x01, 0x03, 0x05, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderPress([true, false, false, false]))),
+        ([0x01, 0x03, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderPress([false, false, true, true]))),
+        // Encoder twists
+        ([0x01, 0x03, 0x05, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderTwist([1, 0, 0, 0]))),
+        ([0x01, 0x03, 0x05, 0x00, 0x01, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderTwist([0, -1, 0, 0]))),
+        ([0x01, 0x03, 0x05, 0x00, 0x01, 0x00, 0x00, 0x05, 0xfb, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderTwist([0, 0, 5, -5]))),
+        ([0x01, 0x03, 0x05, 0x00, 0x01, 0x7f, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::EncoderTwist([127, -128, 0, 0]))),
+        // LCD touches
+        ([0x01, 0x02, 0x0e, 0x00, 0x01, 0x00, 0x5e, 0x01, 0x32, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::LcdTouch { x: 350, y: 50 })),
+        ([0x01, 0x02, 0x0e, 0x00, 0x02, 0x00, 0x1f, 0x03, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Ok(Input::LcdLongPress { x: 799, y: 10 })),
+        ([0x01, 0x02, 0x0e, 0x00, 0x03, 0x00, 0x64, 0x00, 0x28, 0x00, 0x58, 0x02, 0x3c, 0x00],
+            Ok(Input::LcdSwipe { from: (100, 40), to: (600, 60) })),
+        // Malformed reports
+        ([0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Err(InputError::UnknownType(0x01))),
+        ([0x01, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Err(InputError::UnknownType(0xff))),
+        ([0x01, 0x03, 0x05, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Err(InputError::UnknownEncoderEvent(0x02))),
+        ([0x01, 0x02, 0x0e, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            Err(InputError::UnknownTouchEvent(0x00))),
+    ];
+
+    #[test]
+    fn decode_reports() {
+        for (report, expected) in REPORTS {
+            assert_eq!(
+                &Input::try_from(*report),
+                expected,
+                "report {:02x?}",
+                report
+            );
+        }
+    }
+
+    #[test]
+    fn short_reports() {
+        for length in 0..INPUT_REPORT_LENGTH {
+            let report = vec![0x01; length];
+            assert_eq!(Input::decode(&report), Err(InputError::TooShort(length)));
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_ignored() {
+        let mut report = REPORTS[1].0.to_vec();
+        report.extend([0xff; 50]);
+        assert_eq!(&Input::decode(&report), &REPORTS[1].1);
+    }
+}
@@ -0,0 +1,365 @@
+//! Composing key images from a background, icon, title and badges.
+
+use std::f32::consts::PI;
+
+use anyhow::Result;
+use image::{imageops, RgbImage, RgbaImage};
+
+use super::{
+    icon::{icon_cache, IconSource},
+    text::{font_renderer, HorizontalAlign, TextStyle},
+    KEY_SIZE,
+};
+
+const TITLE_PADDING: u32 = 4;
+const TITLE_WIDTH: u32 = KEY_SIZE - TITLE_PADDING * 2;
+const TITLE_MAX_SIZE: f32 = 24.0;
+const TITLE_MIN_SIZE: f32 = 10.0;
+
+#[derive(Debug, Clone)]
+pub enum Background {
+    Color(image::Rgb<u8>),
+    // Vertical gradient from the top color to the bottom color
+    Gradient(image::Rgb<u8>, image::Rgb<u8>),
+    // Scaled to fill the whole key
+    Image(RgbImage),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TitlePosition {
+    Top,
+    Middle,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Badge {
+    // Red bubble with a number in the top right corner
+    Count(u32),
+    // Small colored dot in the top left corner
+    Status(image::Rgb<u8>),
+    // Ring around the edge of the key, from 0.0 to 1.0
+    Progress(f32, image::Rgb<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyImage {
+    background: Background,
+    icon: Option<IconSource>,
+    title: Option<(String, TitlePosition)>,
+    title_style: TextStyle,
+    title_scroll: Option<f32>,
+    badges: Vec<Badge>,
+}
+
+impl Default for KeyImage {
+    fn default() -> Self {
+        Self {
+            background: Background::Color(image::Rgb([0, 0, 0])),
+            icon: None,
+            title: None,
+            title_style: TextStyle::new()
+                .size(TITLE_MAX_SIZE)
+                .align(HorizontalAlign::Center),
+            title_scroll: None,
+            badges: vec![],
+        }
+    }
+}
+
+impl KeyImage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn background(mut self, background: Background) -> Self {
+        self.background = background;
+        self
+    }
+
+    pub fn color(self, color: image::Rgb<u8>) -> Self {
+        self.background(Background::Color(color))
+    }
+
+    pub fn icon(mut self, icon: IconSource) -> Self {
+        self.icon = Some(icon);
+        self
+    }
+
+    pub fn title(mut self, text: impl Into<String>, position: TitlePosition) -> Self {
+        self.title = Some((text.into(), position));
+        self
+    }
+
+    pub fn title_color(mut self, color: image::Rgb<u8>) -> Self {
+        self.title_style.color = color;
+        self
+    }
+
+    // The size is the largest size tried before shrinking the title to fit
+    pub fn title_style(mut self, style: TextStyle) -> Self {
+        self.title_style = style;
+        self
+    }
+
+    // Cut titles that don't fit even at the smallest size short with an ellipsis
+    pub fn title_ellipsis(mut self, ellipsis: bool) -> Self {
+        self.title_style.ellipsis = ellipsis;
+        self
+    }
+
+    // Scroll titles that don't fit even at the smallest size, by this many pixels
+    pub fn title_scroll(mut self, offset: f32) -> Self {
+        self.title_scroll = Some(offset);
+        self
+    }
+
+    pub async fn title_overflows(&self) -> bool {
+        let Some((ref text, _)) = self.title else {
+            return false;
+        };
+
+        let mut renderer = font_renderer().lock().await;
+        let mut style = self.title_style.clone();
+        style.size = renderer.fit_size(text, &style, TITLE_WIDTH, KEY_SIZE, TITLE_MIN_SIZE);
+        renderer.measure(text, &style, None).0 > TITLE_WIDTH as f32
+    }
+
+    pub fn badge(mut self, badge: Badge) -> Self {
+        self.badges.push(badge);
+        self
+    }
+
+    pub async fn render(&self) -> Result<RgbImage> {
+        let mut img = render_background(&self.background);
+
+        // Leave room for the title when there is one
+        let title_position = self.title.as_ref().map(|(_, position)| *position);
+        if let Some(ref icon) = self.icon {
+            let (size, y) = match title_position {
+                None | Some(TitlePosition::Middle) => (96, 12),
+                Some(TitlePosition::Top) => (72, 40),
+                Some(TitlePosition::Bottom) => (72, 8),
+            };
+            let icon = icon_cache().load(icon, size).await?;
+            imageops::overlay(&mut img, icon.as_ref(), ((KEY_SIZE - size) / 2) as i64, y);
+        }
+
+        if let Some((ref text, position)) = self.title {
+            let label = render_title(text, &self.title_style, self.title_scroll).await;
+            let (width, height) = label.dimensions();
+            let x = (KEY_SIZE as i64 - width as i64) / 2;
+            let y = match position {
+                TitlePosition::Top => TITLE_PADDING as i64,
+                TitlePosition::Middle => (KEY_SIZE as i64 - height as i64) / 2,
+                TitlePosition::Bottom => (KEY_SIZE - TITLE_PADDING) as i64 - height as i64,
+            };
+            imageops::overlay(&mut img, &label, x, y);
+        }
+
+        for badge in &self.badges {
+            draw_badge(&mut img, badge).await;
+        }
+
+        Ok(image::DynamicImage::ImageRgba8(img).to_rgb8())
+    }
+}
+
+fn render_background(background: &Background) -> RgbaImage {
+    match background {
+        Background::Color(color) => {
+            let [r, g, b] = color.0;
+            RgbaImage::from_pixel(KEY_SIZE, KEY_SIZE, image::Rgba([r, g, b, 0xFF]))
+        }
+        Background::Gradient(top, bottom) => RgbaImage::from_fn(KEY_SIZE, KEY_SIZE, |_, y| {
+            let t = y as f32 / (KEY_SIZE - 1) as f32;
+            let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+            image::Rgba([
+                mix(top[0], bottom[0]),
+                mix(top[1], bottom[1]),
+                mix(top[2], bottom[2]),
+                0xFF,
+            ])
+        }),
+        Background::Image(img) => {
+            let scaled = imageops::resize(img, KEY_SIZE, KEY_SIZE, imageops::FilterType::Triangle);
+            image::DynamicImage::ImageRgb8(scaled).to_rgba8()
+        }
+    }
+}
+
+async fn render_title(text: &str, style: &TextStyle, scroll: Option<f32>) -> RgbaImage {
+    let mut renderer = font_renderer().lock().await;
+
+    // Shrink the text until it fits on the key
+    let mut style = style.clone();
+    style.size = renderer.fit_size(text, &style, TITLE_WIDTH, KEY_SIZE, TITLE_MIN_SIZE);
+
+    let label = renderer.render_label(text, &style);
+    match scroll {
+        Some(offset) if label.width() > TITLE_WIDTH => renderer
+            .marquee(text, &style, TITLE_WIDTH, label.height())
+            .frame_at(offset),
+        _ if style.ellipsis && label.width() > TITLE_WIDTH => {
+            let mut img = RgbaImage::new(TITLE_WIDTH, label.height());
+            renderer.draw_text(&mut img, 0, 0, TITLE_WIDTH, label.height(), text, &style);
+            img
+        }
+        _ => label,
+    }
+}
+
+async fn draw_badge(img: &mut RgbaImage, badge: &Badge) {
+    match *badge {
+        Badge::Count(count) => {
+            let red = image::Rgba([0xE0, 0x20, 0x20, 0xFF]);
+            fill_circle(img, 100.0, 20.0, 16.0, red);
+
+            let text = if count > 99 {
+                "99+".to_string()
+            } else {
+                count.to_string()
+            };
+            let style = TextStyle::new().size(16.0).bold();
+            let label = font_renderer().lock().await.render_label(&text, &style);
+            let (width, height) = label.dimensions();
+            imageops::overlay(img, &label, 100 - width as i64 / 2, 20 - height as i64 / 2);
+        }
+        Badge::Status(color) => {
+            let [r, g, b] = color.0;
+            fill_circle(img, 14.0, 14.0, 8.0, image::Rgba([r, g, b, 0xFF]));
+        }
+        Badge::Progress(progress, color) => {
+            let [r, g, b] = color.0;
+            draw_ring(img, progress.clamp(0.0, 1.0), image::Rgba([r, g, b, 0xFF]));
+        }
+    }
+}
+
+fn fill_circle(img: &mut RgbaImage, cx: f32, cy: f32, radius: f32, color: image::Rgba<u8>) {
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let dist = ((x as f32 + 0.5 - cx).powi(2) + (y as f32 + 0.5 - cy).powi(2)).sqrt();
+
+        // Antialias the edge over a single pixel
+        let coverage = (radius - dist + 0.5).clamp(0.0, 1.0);
+        if coverage > 0.0 {
+            blend(pixel, color, coverage);
+        }
+    }
+}
+
+fn draw_ring(img: &mut RgbaImage, progress: f32, color: image::Rgba<u8>) {
+    let center = KEY_SIZE as f32 / 2.0;
+    let outer = center - 2.0;
+    let inner = outer - 6.0;
+
+    for (x, y, pixel) in img.enumerate_pixels_mut() {
+        let dx = x as f32 + 0.5 - center;
+        let dy = y as f32 + 0.5 - center;
+        let dist = (dx * dx + dy * dy).sqrt();
+        let coverage = (outer - dist + 0.5).clamp(0.0, 1.0) * (dist - inner + 0.5).clamp(0.0, 1.0);
+        if coverage <= 0.0 {
+            continue;
+        }
+
+        // Clockwise from 12 o'clock
+        let angle = (dx.atan2(-dy) + 2.0 * PI) % (2.0 * PI);
+        if angle <= progress * 2.0 * PI {
+            blend(pixel, color, coverage);
+        }
+    }
+}
+
+fn blend(pixel: &mut image::Rgba<u8>, color: image::Rgba<u8>, coverage: f32) {
+    let alpha = coverage * color[3] as f32 / 255.0;
+    for i in 0..3 {
+        pixel[i] = (pixel[i] as f32 * (1.0 - alpha) + color[i] as f32 * alpha).round() as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, init_fonts};
+
+    #[tokio::test]
+    async fn title_with_count_badge() {
+        init_fonts();
+        let img = KeyImage::new()
+            .color(image::Rgb([0x30, 0x30, 0x60]))
+            .title("Inbox", TitlePosition::Bottom)
+            .badge(Badge::Count(7))
+            .render()
+            .await
+            .unwrap();
+        assert_golden("key_title_count_badge", &img);
+    }
+
+    #[tokio::test]
+    async fn long_title_shrinks() {
+        init_fonts();
+        let img = KeyImage::new()
+            .title("feature/very-long-branch-name", TitlePosition::Middle)
+            .render()
+            .await
+            .unwrap();
+        assert_golden("key_long_title", &img);
+    }
+
+    #[tokio::test]
+    async fn gradient_with_status_and_progress() {
+        init_fonts();
+        let img = KeyImage::new()
+            .background(Background::Gradient(
+                image::Rgb([0x00, 0x80, 0xFF]),
+                image::Rgb([0x00, 0x10, 0x40]),
+            ))
+            .title("Build", TitlePosition::Top)
+            .badge(Badge::Status(image::Rgb([0x20, 0xC0, 0x20])))
+            .badge(Badge::Progress(0.65, image::Rgb([0xFF, 0xFF, 0xFF])))
+            .render()
+            .await
+            .unwrap();
+        assert_golden("key_gradient_status_progress", &img);
+    }
+
+    #[tokio::test]
+    async fn svg_icon_with_title() {
+        init_fonts();
+        let icon = IconSource::file(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/speaker.svg"
+        ));
+        let img = KeyImage::new()
+            .icon(icon)
+            .title("Speakers", TitlePosition::Bottom)
+            .render()
+            .await
+            .unwrap();
+        assert_golden("key_svg_icon", &img);
+    }
+
+    #[tokio::test]
+    async fn tall_title_at_the_bottom() {
+        init_fonts();
+        // Taller than the room under the padding, it should hang off the top instead
+        let img = KeyImage::new()
+            .title("Tall\nTitle", TitlePosition::Bottom)
+            .title_style(TextStyle::new().size(60.0).wrap(true))
+            .render()
+            .await
+            .unwrap();
+        assert_eq!(img.dimensions(), (KEY_SIZE, KEY_SIZE));
+    }
+
+    #[tokio::test]
+    async fn ellipsized_titles_fit() {
+        init_fonts();
+        let text = "Family 17h/19h HD Audio Controller Analog Stereo";
+        let style = TextStyle::new().size(TITLE_MAX_SIZE).ellipsis(true);
+        assert!(render_title(text, &style, None).await.width() <= TITLE_WIDTH);
+
+        let clipped = render_title(text, &style.ellipsis(false), None).await;
+        assert!(clipped.width() > TITLE_WIDTH);
+    }
+}
@@ -1,13 +1,275 @@
-use std::sync::OnceLock;
+//! Text rendering with cosmic-text.
 
-use cosmic_text::{Attrs, Buffer, Color, FontSystem, Metrics, Shaping, SwashCache};
-use image::RgbImage;
-use imageproc::{drawing, rect::Rect};
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+
+use anyhow::{anyhow, Result};
+use cosmic_text::{
+    fontdb, Attrs, Buffer, Color, Family, FontSystem, LayoutGlyph, Metrics, Shaping, SwashCache,
+    Weight, Wrap,
+};
+use image::{imageops, GenericImage, Pixel, RgbImage, RgbaImage};
 use tokio::sync::Mutex;
 
+static RENDERER: OnceLock<Mutex<FontRenderer>> = OnceLock::new();
+
 pub fn font_renderer() -> &'static Mutex<FontRenderer> {
-    static RENDERER: OnceLock<Mutex<FontRenderer>> = OnceLock::new();
-    RENDERER.get_or_init(|| Mutex::new(FontRenderer::new()))
+    RENDERER.get_or_init(|| Mutex::new(FontRenderer::with_config(default_config())))
+}
+
+#[cfg(not(test))]
+fn default_config() -> FontConfig {
+    FontConfig::default()
+}
+
+// Tests run in any order, so whichever renders first has to pick the golden images' fonts
+#[cfg(test)]
+fn default_config() -> FontConfig {
+    crate::golden::font_config()
+}
+
+// Has to be called before anything renders text, otherwise the default config is used
+pub fn init_font_renderer(config: FontConfig) -> Result<()> {
+    RENDERER
+        .set(Mutex::new(FontRenderer::with_config(config)))
+        .map_err(|_| anyhow!("Font renderer already initialized"))
+}
+
+#[cfg(feature = "bundled-fonts")]
+pub const BUNDLED_FAMILY: &str = "DejaVu Sans";
+
+#[cfg(feature = "bundled-fonts")]
+const BUNDLED_FONTS: &[&[u8]] = &[
+    include_bytes!("../../assets/fonts/DejaVuSans.ttf"),
+    include_bytes!("../../assets/fonts/DejaVuSans-Bold.ttf"),
+];
+
+#[derive(Debug, Clone)]
+pub struct FontConfig {
+    system_fonts: bool,
+    #[cfg_attr(not(feature = "bundled-fonts"), allow(dead_code))]
+    bundled_fonts: bool,
+    font_dirs: Vec<PathBuf>,
+    default_family: Option<String>,
+}
+
+// Bundled fonts pick the default family, system fonts are still there as a fallback
+#[cfg(feature = "bundled-fonts")]
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self::bundled().system_fonts(true)
+    }
+}
+
+#[cfg(not(feature = "bundled-fonts"))]
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self::system()
+    }
+}
+
+impl FontConfig {
+    pub fn system() -> Self {
+        Self {
+            system_fonts: true,
+            bundled_fonts: false,
+            font_dirs: vec![],
+            default_family: None,
+        }
+    }
+
+    // Only the fonts embedded in the binary, so rendering is the same everywhere
+    #[cfg(feature = "bundled-fonts")]
+    pub fn bundled() -> Self {
+        Self {
+            system_fonts: false,
+            bundled_fonts: true,
+            font_dirs: vec![],
+            default_family: Some(BUNDLED_FAMILY.to_string()),
+        }
+    }
+
+    pub fn system_fonts(mut self, enabled: bool) -> Self {
+        self.system_fonts = enabled;
+        self
+    }
+
+    pub fn font_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.font_dirs.push(path.into());
+        self
+    }
+
+    // Family used when a TextStyle doesn't pick one
+    pub fn default_family(mut self, family: impl Into<String>) -> Self {
+        self.default_family = Some(family.into());
+        self
+    }
+
+    fn database(&self) -> fontdb::Database {
+        let mut db = fontdb::Database::new();
+
+        if self.system_fonts {
+            db.load_system_fonts();
+        }
+
+        #[cfg(feature = "bundled-fonts")]
+        if self.bundled_fonts {
+            for font in BUNDLED_FONTS {
+                db.load_font_data(font.to_vec());
+            }
+        }
+
+        for dir in &self.font_dirs {
+            db.load_fonts_dir(dir);
+        }
+
+        // Unstyled text uses the sans serif family
+        if let Some(ref family) = self.default_family {
+            db.set_sans_serif_family(family.clone());
+        }
+
+        db
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HorizontalAlign {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerticalAlign {
+    Top,
+    Middle,
+    Bottom,
+}
+
+#[derive(Debug, Clone)]
+pub struct TextStyle {
+    pub family: Option<String>,
+    pub size: f32,
+    pub weight: u16,
+    pub color: image::Rgb<u8>,
+    pub background: Option<image::Rgb<u8>>,
+    pub align: HorizontalAlign,
+    pub vertical_align: VerticalAlign,
+    // Line height as a multiple of the font size
+    pub line_spacing: f32,
+    pub wrap: bool,
+    pub ellipsis: bool,
+    pub max_lines: Option<usize>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        Self {
+            family: None,
+            size: 20.0,
+            weight: 400,
+            color: image::Rgb([0xFF, 0xFF, 0xFF]),
+            background: None,
+            align: HorizontalAlign::Left,
+            vertical_align: VerticalAlign::Top,
+            line_spacing: 1.25,
+            wrap: false,
+            ellipsis: false,
+            max_lines: None,
+        }
+    }
+}
+
+impl TextStyle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn family(mut self, family: impl Into<String>) -> Self {
+        self.family = Some(family.into());
+        self
+    }
+
+    pub fn size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn weight(mut self, weight: u16) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    pub fn bold(self) -> Self {
+        self.weight(700)
+    }
+
+    pub fn color(mut self, color: image::Rgb<u8>) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn background(mut self, color: image::Rgb<u8>) -> Self {
+        self.background = Some(color);
+        self
+    }
+
+    pub fn align(mut self, align: HorizontalAlign) -> Self {
+        self.align = align;
+        self
+    }
+
+    pub fn vertical_align(mut self, align: VerticalAlign) -> Self {
+        self.vertical_align = align;
+        self
+    }
+
+    pub fn centered(self) -> Self {
+        self.align(HorizontalAlign::Center)
+            .vertical_align(VerticalAlign::Middle)
+    }
+
+    pub fn line_spacing(mut self, spacing: f32) -> Self {
+        self.line_spacing = spacing;
+        self
+    }
+
+    pub fn wrap(mut self, wrap: bool) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn ellipsis(mut self, ellipsis: bool) -> Self {
+        self.ellipsis = ellipsis;
+        self
+    }
+
+    pub fn max_lines(mut self, lines: usize) -> Self {
+        self.max_lines = Some(lines);
+        self
+    }
+
+    fn line_height(&self) -> f32 {
+        (self.size * self.line_spacing).ceil()
+    }
+
+    fn attrs(&self) -> Attrs {
+        let attrs = Attrs::new().weight(Weight(self.weight));
+        match self.family {
+            Some(ref family) => attrs.family(Family::Name(family)),
+            None => attrs,
+        }
+    }
+}
+
+// A laid out line of text, with glyph positions relative to the start of the line
+#[derive(Debug, Clone)]
+struct Line {
+    glyphs: Vec<LayoutGlyph>,
+    width: f32,
+    baseline: f32,
 }
 
 #[derive(Debug)]
@@ -18,8 +280,13 @@ pub struct FontRenderer {
 
 impl FontRenderer {
     pub fn new() -> Self {
-        // A FontSystem provides access to detected system fonts, create one per application
-        let font_system = FontSystem::new();
+        Self::with_config(FontConfig::default())
+    }
+
+    pub fn with_config(config: FontConfig) -> Self {
+        // A FontSystem provides access to the configured fonts, create one per application
+        let font_system =
+            FontSystem::new_with_locale_and_db("en-US".to_string(), config.database());
 
         // A SwashCache stores rasterized glyphs, create one per application
         let swash_cache = SwashCache::new();
@@ -30,49 +297,485 @@ impl FontRenderer {
         }
     }
 
+    pub fn load_font_file(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.font_system.db_mut().load_font_file(path)?;
+        Ok(())
+    }
+
     pub fn render_text(&mut self, width: u32, height: u32, text: String) -> RgbImage {
+        let style = TextStyle::new().size(50.0).line_spacing(1.6);
+        self.render_text_styled(width, height, &text, &style)
+    }
+
+    pub fn render_text_styled(
+        &mut self,
+        width: u32,
+        height: u32,
+        text: &str,
+        style: &TextStyle,
+    ) -> RgbImage {
+        let background = style.background.unwrap_or(image::Rgb([0, 0, 0]));
+        let mut img = RgbImage::from_pixel(width, height, background);
+        self.draw_text(&mut img, 0, 0, width, height, text, style);
+        img
+    }
+
+    // Single line of text in a transparent image that fits it exactly
+    pub fn render_label(&mut self, text: &str, style: &TextStyle) -> RgbaImage {
+        let (width, height) = self.measure(text, style, None);
+        let width = (width.ceil() as u32).max(1);
+        let height = (height.ceil() as u32).max(1);
+
+        let mut img = RgbaImage::new(width, height);
+        self.draw_text(&mut img, 0, 0, width, height, text, style);
+        img
+    }
+
+    pub fn render_glyph(
+        &mut self,
+        family: &str,
+        text: &str,
+        size: u32,
+        color: image::Rgb<u8>,
+    ) -> RgbaImage {
+        // Glyphs fill the whole square, so the line height matches the font size
+        let style = TextStyle::new()
+            .family(family)
+            .size(size as f32)
+            .line_spacing(1.0)
+            .color(color)
+            .centered();
+
+        let mut img = RgbaImage::new(size, size);
+        self.draw_text(&mut img, 0, 0, size, size, text, &style);
+        img
+    }
+
+    // Width and height of the text when laid out, wrapping at max_width if the style wraps
+    pub fn measure(&mut self, text: &str, style: &TextStyle, max_width: Option<f32>) -> (f32, f32) {
+        let lines = self.layout(text, style, max_width);
+        let width = lines.iter().map(|line| line.width).fold(0.0, f32::max);
+        let mut count = lines.len().max(1);
+        if let Some(max_lines) = style.max_lines {
+            count = count.min(max_lines);
+        }
+        (width, count as f32 * style.line_height())
+    }
+
+    // Largest size at or below the style's size where the text fits in the box
+    pub fn fit_size(
+        &mut self,
+        text: &str,
+        style: &TextStyle,
+        width: u32,
+        height: u32,
+        min_size: f32,
+    ) -> f32 {
+        let mut style = style.clone();
+        loop {
+            let (text_width, text_height) = self.measure(text, &style, Some(width as f32));
+            let fits = text_width <= width as f32 && text_height <= height as f32;
+            if fits || style.size <= min_size {
+                return style.size.max(min_size);
+            }
+            style.size = (style.size - 1.0).max(min_size);
+        }
+    }
+
+    pub fn marquee(&mut self, text: &str, style: &TextStyle, width: u32, height: u32) -> Marquee {
+        // Marquees are always a single line
+        let style = style.clone().wrap(false).max_lines(1);
+        let strip = self.render_label(text, &style);
+        Marquee {
+            strip,
+            width,
+            height,
+            style,
+            gap: MARQUEE_GAP,
+            speed: MARQUEE_SPEED,
+            offset: 0.0,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn draw_text<I>(
+        &mut self,
+        img: &mut I,
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        text: &str,
+        style: &TextStyle,
+    ) where
+        I: GenericImage,
+        I::Pixel: Pixel<Subpixel = u8>,
+    {
+        if let Some(background) = style.background {
+            fill_rect(img, x, y, width, height, background);
+        }
+
+        let line_height = style.line_height();
+        let mut lines = self.layout(text, style, Some(width as f32));
+
+        // Only keep the lines that fit
+        let mut visible = ((height as f32 / line_height).floor() as usize).max(1);
+        if let Some(max_lines) = style.max_lines {
+            visible = visible.min(max_lines);
+        }
+        let hidden = lines.len() > visible;
+        lines.truncate(visible);
+
+        // Trim overflowing lines, and the last line when some are hidden
+        if style.ellipsis {
+            let ellipsis = self.layout("\u{2026}", style, None).pop();
+            let last = lines.len().saturating_sub(1);
+            for (i, line) in lines.iter_mut().enumerate() {
+                if line.width > width as f32 || (hidden && i == last) {
+                    if let Some(ref ellipsis) = ellipsis {
+                        ellipsize(line, ellipsis, width as f32);
+                    }
+                }
+            }
+        }
+
+        let total_height = lines.len() as f32 * line_height;
+        let offset_y = match style.vertical_align {
+            VerticalAlign::Top => 0.0,
+            VerticalAlign::Middle => (height as f32 - total_height) / 2.0,
+            VerticalAlign::Bottom => height as f32 - total_height,
+        };
+
+        let [r, g, b] = style.color.0;
+        let text_color = Color::rgb(r, g, b);
+
+        for (i, line) in lines.iter().enumerate() {
+            let offset_x = match style.align {
+                HorizontalAlign::Left => 0.0,
+                HorizontalAlign::Center => (width as f32 - line.width) / 2.0,
+                HorizontalAlign::Right => width as f32 - line.width,
+            };
+            let line_y = offset_y + i as f32 * line_height + line.baseline;
+
+            for glyph in &line.glyphs {
+                let physical = glyph.physical((x as f32 + offset_x, y as f32 + line_y), 1.0);
+                let glyph_color = glyph.color_opt.unwrap_or(text_color);
+
+                self.swash_cache.with_pixels(
+                    &mut self.font_system,
+                    physical.cache_key,
+                    glyph_color,
+                    |px, py, color| {
+                        let px = physical.x + px;
+                        let py = physical.y + py;
+
+                        // Clip to the text box
+                        if px < x || py < y || px >= x + width as i32 || py >= y + height as i32 {
+                            return;
+                        }
+                        blend_pixel(img, px, py, color);
+                    },
+                );
+            }
+        }
+    }
+
+    fn layout(&mut self, text: &str, style: &TextStyle, max_width: Option<f32>) -> Vec<Line> {
         // Text metrics indicate the font size and line height of a buffer
-        let metrics = Metrics::new(50.0, 80.0);
+        let line_height = style.line_height();
+        let metrics = Metrics::new(style.size, line_height);
 
-        // A Buffer provides shaping and layout for a UTF-8 string, create one per text widget
+        // A Buffer provides shaping and layout for a UTF-8 string
         let mut buffer = Buffer::new(&mut self.font_system, metrics);
-
-        // Borrow buffer together with the font system for more convenient method calls
         let mut buffer = buffer.borrow_with(&mut self.font_system);
 
-        // Set a size for the text buffer, in pixels
-        buffer.set_size(width as f32, height as f32);
+        // Lay out everything so overflow can be detected, the drawing is clipped later
+        let wrap_width = match max_width {
+            Some(width) if style.wrap => width,
+            _ => f32::MAX,
+        };
+        buffer.set_size(wrap_width, f32::MAX);
+        buffer.set_wrap(if style.wrap { Wrap::Word } else { Wrap::None });
+        buffer.set_text(text, style.attrs(), Shaping::Advanced);
+        buffer.shape_until_scroll(true);
 
-        // Attributes indicate what font to choose
-        let attrs = Attrs::new();
+        buffer
+            .layout_runs()
+            .map(|run| Line {
+                glyphs: run.glyphs.to_vec(),
+                width: run.line_w,
+                baseline: run.line_y - run.line_top,
+            })
+            .collect()
+    }
+}
 
-        // Add some text!
-        buffer.set_text(&text, attrs, Shaping::Advanced);
+const MARQUEE_GAP: u32 = 40;
+const MARQUEE_SPEED: f32 = 2.0;
 
-        // Perform shaping as desired
-        buffer.shape_until_scroll(true);
+// Text that scrolls sideways when it's wider than its box
+#[derive(Debug, Clone)]
+pub struct Marquee {
+    strip: RgbaImage,
+    width: u32,
+    height: u32,
+    style: TextStyle,
+    gap: u32,
+    speed: f32,
+    offset: f32,
+}
 
-        // Create a default text color
-        let text_color = Color::rgb(0xFF, 0xFF, 0xFF);
+impl Marquee {
+    // Pixels to scroll each frame
+    pub fn speed(mut self, speed: f32) -> Self {
+        self.speed = speed;
+        self
+    }
 
-        // Create the image
-        let mut img = image::ImageBuffer::new(width, height);
+    // Pixels between the end of the text and the start of the next loop
+    pub fn gap(mut self, gap: u32) -> Self {
+        self.gap = gap;
+        self
+    }
 
-        // Render text
-        buffer.draw(&mut self.swash_cache, text_color, |x, y, w, h, color| {
-            // Ignore any with alpha of zero
-            if color.a() == 0 {
-                return;
-            }
+    pub fn overflows(&self) -> bool {
+        self.strip.width() > self.width
+    }
 
-            // Draw rects, scaling the colors by the alpha to kinda blend it a bit
-            let rect = Rect::at(x, y).of_size(w, h);
-            let (r, g, b, a) = color.as_rgba_tuple();
-            let scale = |c: u8| (c as i32 * a as i32 / 255).clamp(0, 255) as u8;
-            let pixel = image::Rgb::<u8>([scale(r), scale(g), scale(b)]);
-            drawing::draw_hollow_rect_mut(&mut img, rect, pixel);
-        });
+    pub fn frame_at(&self, offset: f32) -> RgbaImage {
+        let mut img = RgbaImage::new(self.width, self.height);
+        let (strip_width, strip_height) = self.strip.dimensions();
 
+        let y = match self.style.vertical_align {
+            VerticalAlign::Top => 0,
+            VerticalAlign::Middle => (self.height as i64 - strip_height as i64) / 2,
+            VerticalAlign::Bottom => self.height as i64 - strip_height as i64,
+        };
+
+        // Text that fits just sits still where the alignment puts it
+        if !self.overflows() {
+            let x = match self.style.align {
+                HorizontalAlign::Left => 0,
+                HorizontalAlign::Center => (self.width - strip_width) as i64 / 2,
+                HorizontalAlign::Right => (self.width - strip_width) as i64,
+            };
+            imageops::overlay(&mut img, &self.strip, x, y);
+            return img;
+        }
+
+        // Draw the strip twice so the start follows on from the end
+        let cycle = self.cycle();
+        let x = -(offset.rem_euclid(cycle) as i64);
+        imageops::overlay(&mut img, &self.strip, x, y);
+        imageops::overlay(&mut img, &self.strip, x + cycle as i64, y);
+        img
+    }
+
+    // Pixels scrolled before the text is back where it started
+    fn cycle(&self) -> f32 {
+        (self.strip.width() + self.gap) as f32
+    }
+
+    pub fn next_frame(&mut self) -> RgbImage {
+        let frame = self.frame_at(self.offset);
+        if self.overflows() {
+            // Kept within one loop, a growing f32 would eventually stop moving
+            self.offset = (self.offset + self.speed).rem_euclid(self.cycle());
+        }
+
+        let background = self.style.background.unwrap_or(image::Rgb([0, 0, 0]));
+        let mut img = RgbImage::from_pixel(self.width, self.height, background);
+        for (pixel, color) in img.pixels_mut().zip(frame.pixels()) {
+            let alpha = color[3] as f32 / 255.0;
+            for i in 0..3 {
+                pixel[i] =
+                    (pixel[i] as f32 * (1.0 - alpha) + color[i] as f32 * alpha).round() as u8;
+            }
+        }
         img
     }
 }
+
+fn ellipsize(line: &mut Line, ellipsis: &Line, width: f32) {
+    // Drop glyphs until the ellipsis fits after them
+    let available = width - ellipsis.width;
+    line.glyphs.retain(|glyph| glyph.x + glyph.w <= available);
+    let end = line
+        .glyphs
+        .iter()
+        .map(|glyph| glyph.x + glyph.w)
+        .fold(0.0, f32::max);
+
+    line.glyphs.extend(ellipsis.glyphs.iter().map(|glyph| {
+        let mut glyph = glyph.clone();
+        glyph.x += end;
+        glyph
+    }));
+    line.width = end + ellipsis.width;
+}
+
+fn blend_pixel<I>(img: &mut I, x: i32, y: i32, color: Color)
+where
+    I: GenericImage,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    if color.a() == 0 || x < 0 || y < 0 || x as u32 >= img.width() || y as u32 >= img.height() {
+        return;
+    }
+
+    // Straight alpha "over", an RGB image is an opaque destination
+    let alpha = color.a() as f32 / 255.0;
+    let mut pixel = img.get_pixel(x as u32, y as u32);
+    let channels = pixel.channels_mut();
+    let below = match channels.get(3) {
+        Some(&a) => a as f32 / 255.0,
+        None => 1.0,
+    };
+    let out = alpha + below * (1.0 - alpha);
+    for (channel, value) in channels.iter_mut().zip([color.r(), color.g(), color.b()]) {
+        let blended = value as f32 * alpha + *channel as f32 * below * (1.0 - alpha);
+        *channel = (blended / out).round() as u8;
+    }
+    if let Some(a) = channels.get_mut(3) {
+        *a = (out * 255.0).round() as u8;
+    }
+    img.put_pixel(x as u32, y as u32, pixel);
+}
+
+fn fill_rect<I>(img: &mut I, x: i32, y: i32, width: u32, height: u32, color: image::Rgb<u8>)
+where
+    I: GenericImage,
+    I::Pixel: Pixel<Subpixel = u8>,
+{
+    let [r, g, b] = color.0;
+    let fill = Color::rgb(r, g, b);
+    for py in y..y + height as i32 {
+        for px in x..x + width as i32 {
+            blend_pixel(img, px, py, fill);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::golden::{assert_golden, init_fonts};
+
+    #[test]
+    fn render_text_default() {
+        init_fonts();
+        let img = font_renderer()
+            .blocking_lock()
+            .render_text(800, 100, "Count: 42".to_string());
+        assert_golden("text_default", &img);
+    }
+
+    #[test]
+    fn render_text_centered() {
+        init_fonts();
+        let style = TextStyle::new()
+            .size(32.0)
+            .bold()
+            .color(image::Rgb([0xFF, 0xC0, 0x00]))
+            .background(image::Rgb([0x20, 0x20, 0x40]))
+            .centered();
+        let img = font_renderer()
+            .blocking_lock()
+            .render_text_styled(200, 100, "Volume", &style);
+        assert_golden("text_centered", &img);
+    }
+
+    #[test]
+    fn render_text_wrapped_with_ellipsis() {
+        init_fonts();
+        let style = TextStyle::new()
+            .size(18.0)
+            .wrap(true)
+            .ellipsis(true)
+            .max_lines(2);
+        let img = font_renderer().blocking_lock().render_text_styled(
+            120,
+            60,
+            "A song title that is much too long for a single key",
+            &style,
+        );
+        assert_golden("text_wrapped_ellipsis", &img);
+    }
+
+    #[test]
+    fn draw_text_over_image() {
+        init_fonts();
+        let mut img = RgbImage::from_fn(200, 100, |x, _| image::Rgb([x as u8, 0x40, 0x80]));
+        let style = TextStyle::new().size(24.0).align(HorizontalAlign::Right);
+        font_renderer()
+            .blocking_lock()
+            .draw_text(&mut img, 10, 10, 180, 80, "Blended", &style);
+        assert_golden("text_draw_over_image", &img);
+    }
+
+    #[test]
+    fn blends_onto_transparent_images() {
+        let mut img = RgbaImage::new(1, 1);
+        blend_pixel(&mut img, 0, 0, Color::rgba(0xFF, 0x00, 0x00, 0x80));
+        assert_eq!(img.get_pixel(0, 0).0, [0xFF, 0x00, 0x00, 0x80]);
+
+        // Half over half covers three quarters, a third of it red
+        blend_pixel(&mut img, 0, 0, Color::rgba(0x00, 0x00, 0xFF, 0x80));
+        assert_eq!(img.get_pixel(0, 0).0, [0x55, 0x00, 0xAA, 0xC0]);
+    }
+
+    #[test]
+    fn labels_have_no_dark_fringes() {
+        init_fonts();
+        let style = TextStyle::new()
+            .size(24.0)
+            .color(image::Rgb([0xFF, 0xFF, 0xFF]));
+        let label = font_renderer()
+            .blocking_lock()
+            .render_label("Fringe", &style);
+
+        // Edges are only less opaque, never darker
+        let edges = label.pixels().filter(|p| p[3] > 0 && p[3] < 0xFF).count();
+        assert!(edges > 0);
+        assert!(label
+            .pixels()
+            .filter(|p| p[3] > 0)
+            .all(|p| p.0[..3] == [0xFF, 0xFF, 0xFF]));
+    }
+
+    #[test]
+    fn marquee_frame() {
+        init_fonts();
+        let style = TextStyle::new()
+            .size(24.0)
+            .vertical_align(VerticalAlign::Middle);
+        let mut marquee = font_renderer()
+            .blocking_lock()
+            .marquee("Never Gonna Give You Up - Rick Astley", &style, 200, 40)
+            .speed(30.0);
+        assert!(marquee.overflows());
+
+        marquee.next_frame();
+        assert_golden("text_marquee_frame", &marquee.next_frame());
+    }
+
+    #[test]
+    fn marquee_offset_wraps() {
+        init_fonts();
+        let mut marquee = font_renderer()
+            .blocking_lock()
+            .marquee(
+                "Scrolling along forever and ever",
+                &TextStyle::new(),
+                100,
+                30,
+            )
+            .speed(7.0);
+        let cycle = marquee.cycle();
+
+        for _ in 0..(cycle as usize) {
+            marquee.next_frame();
+            assert!(marquee.offset < cycle);
+        }
+    }
+}
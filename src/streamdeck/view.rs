@@ -0,0 +1,164 @@
+//! Per-app views of the deck.
+//!
+//! Every app draws through its own view. A view remembers everything its app drew, but only
+//! passes on the keys and LCD zones it's shown on, so an app that isn't up can't draw over
+//! the one that is. Showing a view on more of the deck puts back what it remembers there.
+
+use std::sync::{Arc, Mutex};
+
+use image::{imageops, RgbImage};
+use tokio::sync::watch;
+
+use super::{
+    solid_image, DeckError, Screen, StreamDeckPlus, KEY_COUNT, KEY_SIZE, LCD_HEIGHT, LCD_WIDTH,
+    LCD_ZONES, ZONE_WIDTH,
+};
+
+/// The keys and LCD zones a view draws on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Visibility {
+    pub keys: [bool; KEY_COUNT as usize],
+    pub lcd_zones: [bool; LCD_ZONES as usize],
+}
+
+impl Visibility {
+    /// The whole deck.
+    pub fn all() -> Self {
+        Self {
+            keys: [true; KEY_COUNT as usize],
+            lcd_zones: [true; LCD_ZONES as usize],
+        }
+    }
+
+    /// Nothing at all, what a new view starts with.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        !self.keys.contains(&true) && !self.lcd_zones.contains(&true)
+    }
+}
+
+#[derive(Debug)]
+pub(super) struct View {
+    screen: Mutex<Screen>,
+    visibility: watch::Sender<Visibility>,
+}
+
+impl View {
+    // Remembers a key image, returning whether it should be drawn
+    pub(super) fn draw_key(&self, index: u8, image: &RgbImage) -> bool {
+        self.screen.lock().unwrap().keys[index as usize] = Some(image.clone());
+        self.visibility.borrow().keys[index as usize]
+    }
+
+    // Remembers an LCD image, returning the runs of it in visible zones
+    pub(super) fn draw_lcd(&self, x: u16, y: u16, image: &RgbImage) -> Vec<(u32, u32)> {
+        imageops::replace(
+            &mut self.screen.lock().unwrap().lcd,
+            image,
+            x as i64,
+            y as i64,
+        );
+        let zones = self.visibility.borrow().lcd_zones;
+        visible_runs(&zones, x as u32, x as u32 + image.width())
+    }
+}
+
+impl StreamDeckPlus {
+    /// A handle for one app, drawing only where [`set_visibility`](Self::set_visibility)
+    /// shows it. It starts hidden, but remembers what's drawn on it.
+    pub fn view(&self) -> Self {
+        Self {
+            view: Some(Arc::new(View {
+                screen: Mutex::new(Screen::default()),
+                visibility: watch::Sender::new(Visibility::none()),
+            })),
+            ..self.clone()
+        }
+    }
+
+    /// Where this handle draws, the whole deck for anything but a view.
+    pub fn visibility(&self) -> watch::Receiver<Visibility> {
+        match &self.view {
+            Some(view) => view.visibility.subscribe(),
+            // Never changes, so the sender doesn't need to stay around
+            None => watch::channel(Visibility::all()).1,
+        }
+    }
+
+    /// Shows a view on `visibility`, redrawing what it remembers on the parts that are new.
+    ///
+    /// Does nothing on a handle that isn't a view.
+    pub async fn set_visibility(&self, visibility: Visibility) -> Result<(), DeckError> {
+        let Some(view) = &self.view else {
+            return Ok(());
+        };
+        let before = view.visibility.send_replace(visibility);
+
+        let black = solid_image(KEY_SIZE, KEY_SIZE, image::Rgb([0, 0, 0]));
+        for index in 0..KEY_COUNT {
+            if !visibility.keys[index as usize] || before.keys[index as usize] {
+                continue;
+            }
+            let image = view.screen.lock().unwrap().keys[index as usize].clone();
+            self.show_button_image(index, image.as_ref().unwrap_or(&black))
+                .await?;
+        }
+
+        let mut shown = visibility.lcd_zones;
+        for (zone, was_visible) in shown.iter_mut().zip(before.lcd_zones) {
+            *zone &= !was_visible;
+        }
+        for (x, width) in visible_runs(&shown, 0, LCD_WIDTH) {
+            let image =
+                imageops::crop_imm(&view.screen.lock().unwrap().lcd, x, 0, width, LCD_HEIGHT)
+                    .to_image();
+            self.show_lcd_image(x as u16, 0, &image).await?;
+        }
+        Ok(())
+    }
+}
+
+// The parts of `start..end` in visible zones, as (x, width) with neighbours merged
+fn visible_runs(zones: &[bool; LCD_ZONES as usize], start: u32, end: u32) -> Vec<(u32, u32)> {
+    let mut runs: Vec<(u32, u32)> = vec![];
+    for (zone, visible) in zones.iter().enumerate() {
+        let from = start.max(zone as u32 * ZONE_WIDTH);
+        let to = end.min((zone as u32 + 1) * ZONE_WIDTH);
+        if !visible || from >= to {
+            continue;
+        }
+
+        match runs.last_mut() {
+            Some((x, width)) if *x + *width == from => *width += to - from,
+            _ => runs.push((from, to - from)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_cover_visible_zones() {
+        let zones = [true, true, false, true];
+        assert_eq!(visible_runs(&zones, 0, LCD_WIDTH), [(0, 400), (600, 200)]);
+        assert_eq!(visible_runs(&zones, 150, 650), [(150, 250), (600, 50)]);
+        assert_eq!(visible_runs(&zones, 450, 550), []);
+        assert_eq!(visible_runs(&[false; 4], 0, LCD_WIDTH), []);
+    }
+
+    #[test]
+    fn visibility() {
+        assert!(Visibility::none().is_hidden());
+        assert!(!Visibility::all().is_hidden());
+
+        let mut mic = Visibility::none();
+        mic.keys[4] = true;
+        assert!(!mic.is_hidden());
+    }
+}
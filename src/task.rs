@@ -0,0 +1,25 @@
+//! Task spawning helpers.
+
+use std::future::Future;
+
+use tokio::task::JoinHandle;
+
+/// Spawns a task, named so it shows up in tokio-console.
+#[cfg(tokio_unstable)]
+pub fn spawn<F>(name: &str, future: F) -> std::io::Result<JoinHandle<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    tokio::task::Builder::new().name(name).spawn(future)
+}
+
+/// Spawns a task, task names need `--cfg tokio_unstable`.
+#[cfg(not(tokio_unstable))]
+pub fn spawn<F>(_name: &str, future: F) -> std::io::Result<JoinHandle<F::Output>>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    Ok(tokio::spawn(future))
+}